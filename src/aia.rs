@@ -0,0 +1,387 @@
+//! Support of the Advanced Interrupt Architecture (AIA): guest interrupt files of the hart's
+//! IMSIC, which deliver MSIs directly to the guest.
+//!
+//! A guest interrupt file is selected for the guest by `hstatus.VGEIN`. While a vCPU runs, its
+//! file drives the guest's external interrupt and is accessed by the guest through `vsiselect`,
+//! `vsireg` and `vstopei` without exits. While it is descheduled, its file raises a supervisor
+//! guest external interrupt (SGEI) on the hart, through `hgeie`, so that the vCPU can be woken.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+
+use crate::detect::detect_guest_external_lines;
+use crate::isa::HostIsaFeatures;
+use crate::percpu::HartState;
+
+/// `VGEIN` field shift in `hstatus`.
+const HSTATUS_VGEIN_SHIFT: usize = 12;
+/// `VGEIN` field mask in `hstatus`.
+const HSTATUS_VGEIN_MASK: usize = 0x3f << HSTATUS_VGEIN_SHIFT;
+
+/// `vsiselect` value of the `eidelivery` register of an interrupt file.
+const ISELECT_EIDELIVERY: usize = 0x70;
+/// `vsiselect` value of the `eithreshold` register of an interrupt file.
+const ISELECT_EITHRESHOLD: usize = 0x72;
+/// `vsiselect` value of the first `eip` register of an interrupt file.
+const ISELECT_EIP0: usize = 0x80;
+/// `vsiselect` value of the first `eie` register of an interrupt file.
+const ISELECT_EIE0: usize = 0xc0;
+/// Number of 64-bit `eip`/`eie` registers, only the even-numbered ones exist on RV64.
+const EIX_REGS: usize = 32;
+/// Number of guest interrupt files a hart can have, including the nonexistent file 0.
+const MAX_GUEST_FILES: usize = usize::BITS as usize;
+/// Interrupt identity field shift in `stopei`.
+const TOPEI_ID_SHIFT: usize = 16;
+/// Interrupt identity field mask in `stopei`, after shifting.
+const TOPEI_ID_MASK: usize = 0x7ff;
+
+/// Allocates the owner IDs of the vCPUs in [`HartGuestFiles`], 0 meaning no owner.
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+/// The state of a guest interrupt file, saved to move a vCPU to another file.
+///
+/// `vstopei` is not saved, as it is derived from the other registers. The default state is the
+/// state of a reset file.
+#[derive(Clone, Debug, Default)]
+struct ImsicFileState {
+    eidelivery: usize,
+    eithreshold: usize,
+    eip: [usize; EIX_REGS],
+    eie: [usize; EIX_REGS],
+}
+
+impl ImsicFileState {
+    /// Saves the state of guest interrupt file `file` of the current hart.
+    ///
+    /// `vsiselect` is clobbered, the caller is responsible for saving it.
+    pub fn save(file: usize) -> Self {
+        with_vgein(file, || {
+            let mut state = Self {
+                eidelivery: read_vsireg(ISELECT_EIDELIVERY),
+                eithreshold: read_vsireg(ISELECT_EITHRESHOLD),
+                eip: [0; EIX_REGS],
+                eie: [0; EIX_REGS],
+            };
+            for i in 0..EIX_REGS {
+                state.eip[i] = read_vsireg(ISELECT_EIP0 + i * 2);
+                state.eie[i] = read_vsireg(ISELECT_EIE0 + i * 2);
+            }
+            state
+        })
+    }
+
+    /// Restores the state to guest interrupt file `file` of the current hart.
+    ///
+    /// `vsiselect` is clobbered, the caller is responsible for restoring it.
+    pub fn restore(&self, file: usize) {
+        with_vgein(file, || {
+            // Disable delivery while the file is being updated.
+            write_vsireg(ISELECT_EIDELIVERY, 0);
+            write_vsireg(ISELECT_EITHRESHOLD, self.eithreshold);
+            for i in 0..EIX_REGS {
+                write_vsireg(ISELECT_EIP0 + i * 2, self.eip[i]);
+                write_vsireg(ISELECT_EIE0 + i * 2, self.eie[i]);
+            }
+            write_vsireg(ISELECT_EIDELIVERY, self.eidelivery);
+        })
+    }
+}
+
+/// The guest interrupt files of a hart, with the vCPUs whose state they hold.
+pub(crate) struct HartGuestFiles {
+    // The owner ID of the vCPU whose state is in each file, or 0 if none.
+    owners: [AtomicUsize; MAX_GUEST_FILES],
+    // The files released by their owners from other harts, to be reset by the hart.
+    released: AtomicUsize,
+}
+
+impl HartGuestFiles {
+    pub const fn new() -> Self {
+        Self {
+            owners: [const { AtomicUsize::new(0) }; MAX_GUEST_FILES],
+            released: AtomicUsize::new(0),
+        }
+    }
+
+    /// Makes `owner` the owner of file `file`, and returns whether it already was.
+    fn claim(&self, file: usize, owner: usize) -> bool {
+        self.owners[file].swap(owner, Ordering::AcqRel) == owner
+    }
+
+    /// Releases file `file` if it is owned by `owner`. Its state is left for another vCPU until
+    /// the hart resets it in [`Self::reset_released`].
+    fn release(&self, file: usize, owner: usize) {
+        if self.owners[file]
+            .compare_exchange(owner, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.released.fetch_or(1 << file, Ordering::AcqRel);
+        }
+    }
+
+    /// Resets the released files of the current hart which were not claimed again, and stops
+    /// waiting on their guest external interrupts in `hgeie`.
+    pub fn reset_released(&self) {
+        let released = self.released.swap(0, Ordering::AcqRel);
+        if released == 0 {
+            return;
+        }
+        // The hart may have been interrupted while accessing a file.
+        let vsiselect = read_vsiselect();
+        for file in 0..MAX_GUEST_FILES {
+            if released & 1 << file != 0 && self.owners[file].load(Ordering::Acquire) == 0 {
+                ImsicFileState::default().restore(file);
+                unsafe {
+                    core::arch::asm!("csrc 0x607, {}", in(reg) 1usize << file); // 0x607 => hgeie
+                }
+            }
+        }
+        write_vsiselect(vsiselect);
+    }
+}
+
+/// The guest interrupt file assigned to a vCPU.
+///
+/// The vCPU owns the file of the hart it was last bound to until it is bound to another hart or
+/// file, so that the file keeps its state while the vCPU is descheduled. Binding the vCPU to a
+/// file it does not own loads the state saved on unbind, or resets the file for a new vCPU, so
+/// that the vCPU never sees the interrupts of the previous owner.
+#[derive(Debug, Default)]
+pub(crate) struct GuestInterruptFile {
+    file: Option<usize>,
+    // The owner ID of the vCPU, allocated on first bind.
+    owner: usize,
+    // The hart and file owned by the vCPU.
+    owned: Option<(usize, usize)>,
+    // The file state saved on unbind.
+    saved: Option<ImsicFileState>,
+    // The MSIs sent to the file by the hypervisor, in the layout of the `eip` registers.
+    queued: [AtomicUsize; EIX_REGS],
+    // The `eip` registers with queued MSIs.
+    queued_regs: AtomicUsize,
+}
+
+impl GuestInterruptFile {
+    /// Assigns guest interrupt file `file` of the current hart, or none.
+    pub fn assign(&mut self, file: Option<usize>) -> AxResult {
+        if let Some(file) = file {
+            if !HostIsaFeatures::current().ssaia {
+                return ax_err!(Unsupported, "Ssaia is not supported");
+            }
+            if file == 0
+                || file >= usize::BITS as usize
+                || detect_guest_external_lines() & (1 << file) == 0
+            {
+                return ax_err!(InvalidInput, "guest interrupt file is not implemented");
+            }
+        }
+        self.file = file;
+        Ok(())
+    }
+
+    /// Returns `hstatus` of the guest with `VGEIN` selecting the assigned file.
+    pub fn guest_hstatus(&self, hstatus: usize) -> usize {
+        hstatus & !HSTATUS_VGEIN_MASK | self.file.unwrap_or(0) << HSTATUS_VGEIN_SHIFT
+    }
+
+    /// Loads the state of the guest interrupt file when binding the vCPU to the current hart,
+    /// `hart` with state `state`.
+    ///
+    /// If the vCPU was moved to another hart or file since it was last unbound, the previous file
+    /// is released, and the state saved from it is moved to the new one.
+    pub fn bind(&mut self, state: &HartState, hart: usize, vsiselect: usize) {
+        state.guest_files.reset_released();
+        if self.owned != self.file.map(|file| (hart, file)) {
+            self.release();
+        }
+        let Some(file) = self.file else {
+            return;
+        };
+        if self.owner == 0 {
+            self.owner = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+        }
+        let saved = self.saved.take();
+        if !state.guest_files.claim(file, self.owner) {
+            saved.unwrap_or_default().restore(file);
+        }
+        self.owned = Some((hart, file));
+        write_vsiselect(vsiselect);
+        // The file now interrupts the guest directly.
+        unsafe {
+            core::arch::asm!("csrc 0x607, {}", in(reg) 1usize << file); // 0x607 => hgeie
+        }
+    }
+
+    /// Saves the state of the guest interrupt file when unbinding the vCPU from the current hart.
+    ///
+    /// The guest external interrupt of the file is enabled in `hgeie`, so that MSIs received
+    /// while the vCPU is descheduled are reported to the hypervisor. Returns the `vsiselect` of
+    /// the guest.
+    pub fn unbind(&mut self) -> usize {
+        let Some(file) = self.file else {
+            return 0;
+        };
+        let vsiselect = read_vsiselect();
+        self.saved = Some(ImsicFileState::save(file));
+        unsafe {
+            core::arch::asm!("csrs 0x607, {}", in(reg) 1usize << file); // 0x607 => hgeie
+        }
+        vsiselect
+    }
+
+    /// Queues an MSI with identity `eiid`, to be set pending in the file by
+    /// [`Self::deliver_queued`]. This can be called from any CPU.
+    pub fn queue_msi(&self, eiid: usize) -> AxResult {
+        if self.file.is_none() {
+            return ax_err!(BadState, "no guest interrupt file is assigned");
+        }
+        let bits = usize::BITS as usize;
+        if eiid == 0 || eiid >= EIX_REGS * bits {
+            return ax_err!(InvalidInput, "invalid interrupt identity");
+        }
+        self.queued[eiid / bits].fetch_or(1 << (eiid % bits), Ordering::AcqRel);
+        self.queued_regs
+            .fetch_or(1 << (eiid / bits), Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Sets the queued MSIs pending in the file, with the vCPU bound to the current hart.
+    pub fn deliver_queued(&self) {
+        let Some(file) = self.file else {
+            return;
+        };
+        let regs = self.queued_regs.swap(0, Ordering::AcqRel);
+        if regs == 0 {
+            return;
+        }
+        let vsiselect = read_vsiselect();
+        with_vgein(file, || {
+            for (i, queued) in self.queued.iter().enumerate() {
+                if regs & 1 << i != 0 {
+                    set_vsireg_bits(ISELECT_EIP0 + i * 2, queued.swap(0, Ordering::AcqRel));
+                }
+            }
+        });
+        write_vsiselect(vsiselect);
+    }
+
+    /// Releases the file owned by the vCPU, if any.
+    fn release(&mut self) {
+        if let Some((hart, file)) = self.owned.take() {
+            if let Some(state) = HartState::of(hart) {
+                state.guest_files.release(file, self.owner);
+            }
+        }
+    }
+}
+
+impl Drop for GuestInterruptFile {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Claims the highest priority interrupt pending in the supervisor-level interrupt file of the
+/// current hart's IMSIC, through `stopei`.
+///
+/// Returns `None` if there is none, e.g. when the host does not deliver its external interrupts
+/// through the IMSIC. Requires Ssaia.
+pub(crate) fn claim_host_external() -> Option<usize> {
+    let topei: usize;
+    unsafe {
+        // Writing `stopei` claims the interrupt it reads.
+        core::arch::asm!("csrrw {}, 0x15c, zero", out(reg) topei); // 0x15c => stopei
+    }
+    match topei >> TOPEI_ID_SHIFT & TOPEI_ID_MASK {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+/// Returns the guest interrupt files of the current hart with a pending guest external interrupt
+/// the hypervisor waits on, and stops waiting on them.
+///
+/// The host must call this on a supervisor guest external interrupt (SGEI) taken outside
+/// [`run`](axvcpu::AxArchVCpu::run), and wake the vCPUs the returned files are assigned to; the
+/// interrupts taken inside `run` are reported by the [`GUEST_EXTERNAL_EXIT_VECTOR`] exit
+/// instead. SGEIs are enabled on the CPUs virtualization is enabled on.
+///
+/// The interrupts are level-triggered, so they are masked in `hgeie` until the vCPUs owning the
+/// files are unbound again. The files released by their vCPUs are reset instead of reported.
+///
+/// [`GUEST_EXTERNAL_EXIT_VECTOR`]: crate::GUEST_EXTERNAL_EXIT_VECTOR
+pub fn take_guest_external_pending() -> usize {
+    if let Some(state) = HartState::current() {
+        state.guest_files.reset_released();
+    }
+    let pending: usize;
+    unsafe {
+        core::arch::asm!(
+            "csrr {hgeip}, 0xe12", // 0xe12 => hgeip
+            "csrr {pending}, 0x607", // 0x607 => hgeie
+            "and {pending}, {pending}, {hgeip}",
+            "csrc 0x607, {pending}",
+            hgeip = out(reg) _,
+            pending = out(reg) pending,
+        );
+    }
+    pending
+}
+
+/// Runs `f` with `hstatus.VGEIN` of the hypervisor set to `file`, so that `vsireg` accesses the
+/// registers of that guest interrupt file.
+fn with_vgein<T>(file: usize, f: impl FnOnce() -> T) -> T {
+    let hstatus: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, hstatus", out(reg) hstatus);
+        core::arch::asm!(
+            "csrw hstatus, {}",
+            in(reg) hstatus & !HSTATUS_VGEIN_MASK | file << HSTATUS_VGEIN_SHIFT,
+        );
+    }
+    let ret = f();
+    unsafe {
+        core::arch::asm!("csrw hstatus, {}", in(reg) hstatus);
+    }
+    ret
+}
+
+/// Reads `vsiselect`.
+fn read_vsiselect() -> usize {
+    let val;
+    unsafe {
+        core::arch::asm!("csrr {}, 0x250", out(reg) val); // 0x250 => vsiselect
+    }
+    val
+}
+
+/// Writes `vsiselect`.
+fn write_vsiselect(val: usize) {
+    unsafe {
+        core::arch::asm!("csrw 0x250, {}", in(reg) val); // 0x250 => vsiselect
+    }
+}
+
+fn read_vsireg(iselect: usize) -> usize {
+    write_vsiselect(iselect);
+    let val;
+    unsafe {
+        core::arch::asm!("csrr {}, 0x251", out(reg) val); // 0x251 => vsireg
+    }
+    val
+}
+
+fn write_vsireg(iselect: usize, val: usize) {
+    write_vsiselect(iselect);
+    unsafe {
+        core::arch::asm!("csrw 0x251, {}", in(reg) val); // 0x251 => vsireg
+    }
+}
+
+fn set_vsireg_bits(iselect: usize, bits: usize) {
+    write_vsiselect(iselect);
+    unsafe {
+        core::arch::asm!("csrs 0x251, {}", in(reg) bits); // 0x251 => vsireg
+    }
+}
@@ -83,9 +83,20 @@ pub mod traps {
         pub const S_TIMER: usize = INTC_IRQ_BASE + 5;
         /// Supervisor external interrupt in `scause`
         pub const S_EXT: usize = INTC_IRQ_BASE + 9;
+        /// Supervisor guest external interrupt in `scause`
+        pub const S_GUEST_EXT: usize = INTC_IRQ_BASE + 12;
         /// The maximum number of IRQs.
         pub const MAX_IRQ_COUNT: usize = 1024;
         /// The timer IRQ number (supervisor timer interrupt in `scause`).
         pub const TIMER_IRQ_NUM: usize = S_TIMER;
     }
 }
+
+/// `SIE` bit in `sstatus` and `vsstatus`.
+pub const SSTATUS_SIE: usize = 1 << 1;
+/// `SPIE` bit in `sstatus` and `vsstatus`.
+pub const SSTATUS_SPIE: usize = 1 << 5;
+/// `SPP` bit in `sstatus` and `vsstatus`.
+pub const SSTATUS_SPP: usize = 1 << 8;
+/// `MODE` field in `stvec` and `vstvec`.
+pub const STVEC_MODE_MASK: usize = 0b11;
@@ -2,36 +2,181 @@
 //!
 //! First, it disables all S-level interrupts. Remaining traps in RISC-V core
 //! are all exceptions.
-//! Then, it filters out illegal instruction from exceptions.
+//! Then, any exception raised by the probed instructions is recorded, and the faulting
+//! instruction is skipped, whatever its length.
 //! ref: <https://github.com/luojia65/zihai/blob/main/zihai/src/detect.rs>
 
 use core::arch::{asm, naked_asm};
 use riscv::register::{
+    htval,
     scause::{Exception, Scause, Trap},
     sstatus,
     stvec::{self, Stvec, TrapMode},
 };
 
+use crate::consts::traps;
+use crate::page_walk::{ATP_MODE_SHIFT, GStageMode, HGATP_VMID_MASK, HGATP_VMID_SHIFT};
+
+/// Exceptions raised by fetching an instruction, whose address cannot be read to skip it.
+const FETCH_EXCEPTIONS: usize = traps::exception::INST_ACCESSS_FAULT
+    | traps::exception::INST_PAGE_FAULT
+    | traps::exception::INST_GUEST_PAGE_FAULT;
+
+/// Exceptions whose `htval` holds the faulting guest physical address, shifted right by 2 bits.
+/// `htval` is only read for them, as it does not exist without the hypervisor extension.
+const GUEST_PAGE_FAULTS: usize = traps::exception::INST_GUEST_PAGE_FAULT
+    | traps::exception::LOAD_GUEST_PAGE_FAULT
+    | traps::exception::STORE_GUEST_PAGE_FAULT;
+
+/// An exception raised by the instructions run by [`probe_instruction`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProbeFault {
+    /// `scause` of the exception.
+    pub scause: usize,
+    /// `stval` of the exception.
+    pub stval: usize,
+    /// `htval` of the exception for guest-page faults, or 0.
+    pub htval: usize,
+}
+
+/// Runs the instructions in `f`, and returns the last exception they raised, if any.
+///
+/// The instructions raising exceptions are skipped, so `f` should only contain the instructions
+/// to probe and must not depend on their results if they trap. It runs with S-level interrupts
+/// disabled, on the current stack. Instruction fetch faults cannot be skipped, and panic.
+///
+/// `stval` and `htval` are recorded by the trap handler, as the CSRs may be overwritten by an
+/// interrupt once interrupts are enabled again.
+///
+/// For example, a CSR access returns a fault with `scause` 2 (illegal instruction) if the CSR is
+/// not implemented, or 22 (virtual instruction) if it is not accessible in VS-mode.
+pub fn probe_instruction(f: impl FnOnce()) -> Result<(), ProbeFault> {
+    // Exceptions never have the interrupt bit of `scause` set.
+    const NO_TRAP: usize = usize::MAX;
+    let mut fault = ProbeFault {
+        scause: NO_TRAP,
+        stval: 0,
+        htval: 0,
+    };
+    with_detect_trap(&mut fault, f);
+    // Written by the trap handler, behind the back of the compiler.
+    let fault = unsafe { core::ptr::read_volatile(&fault) };
+    match fault.scause {
+        NO_TRAP => Ok(()),
+        _ => Err(fault),
+    }
+}
+
 /// Detect if hypervisor extension exists on current hart environment
 ///
 /// This function tries to read hgatp and returns false if the read operation failed.
 pub fn detect_h_extension() -> bool {
     // run detection by trap on csrr instruction.
-    let ans = with_detect_trap(0, || unsafe {
+    probe_instruction(|| unsafe {
         asm!("csrr  {}, 0x680", out(reg) _, options(nomem, nostack)); // 0x680 => hgatp
-    });
-    // return the answer from output flag. 0 => success, 2 => failed, illegal instruction
-    ans != 2
+    })
+    .is_ok()
+}
+
+/// Detect if the supervisor-level Advanced Interrupt Architecture (Ssaia) exists on current hart
+/// environment
+///
+/// This function tries to read stopei and returns false if the read operation failed.
+pub fn detect_ssaia() -> bool {
+    probe_instruction(|| unsafe {
+        asm!("csrr  {}, 0x15c", out(reg) _, options(nomem, nostack)); // 0x15c => stopei
+    })
+    .is_ok()
+}
+
+/// Detect if the supervisor-level fine-grained address-translation cache invalidation (Svinval)
+/// exists on current hart environment
+///
+/// This function tries to execute sfence.w.inval and returns false if it is an illegal
+/// instruction.
+pub fn detect_svinval() -> bool {
+    probe_instruction(|| unsafe {
+        core::arch::riscv64::sfence_w_inval();
+    })
+    .is_ok()
+}
+
+/// Returns the mask of guest external interrupt lines (guest interrupt files) implemented on
+/// current hart, i.e. bits 1 to GEILEN.
+///
+/// This function writes all ones to hgeie and reads back the implemented bits. It requires the
+/// hypervisor extension.
+pub fn detect_guest_external_lines() -> usize {
+    let mask: usize;
+    unsafe {
+        asm!(
+            "csrr  {old}, 0x607", // 0x607 => hgeie
+            "csrw  0x607, {ones}",
+            "csrr  {mask}, 0x607",
+            "csrw  0x607, {old}",
+            old = out(reg) _,
+            ones = in(reg) usize::MAX,
+            mask = out(reg) mask,
+            options(nomem, nostack),
+        );
+    }
+    mask
+}
+
+/// Detect if the G-stage translation mode `mode` is supported on current hart environment
+///
+/// This function writes the mode to hgatp and reads it back, as unsupported modes are not
+/// written. It requires the hypervisor extension, and clobbers the TLB of the G-stage.
+pub fn detect_gstage_mode(mode: GStageMode) -> bool {
+    let hgatp: usize;
+    unsafe {
+        asm!(
+            "csrr  {old}, 0x680", // 0x680 => hgatp
+            "csrw  0x680, {new}",
+            "csrr  {hgatp}, 0x680",
+            "csrw  0x680, {old}",
+            old = out(reg) _,
+            new = in(reg) mode.hgatp_mode() << ATP_MODE_SHIFT,
+            hgatp = out(reg) hgatp,
+            options(nomem, nostack),
+        );
+        core::arch::riscv64::hfence_gvma_all();
+    }
+    hgatp >> ATP_MODE_SHIFT == mode.hgatp_mode()
+}
+
+/// Returns the number of VMID bits (VMIDLEN) implemented on current hart environment
+///
+/// This function writes all ones to the VMID field of hgatp and counts the bits read back. It
+/// requires the hypervisor extension, and clobbers the TLB of the G-stage.
+pub fn detect_vmid_bits() -> usize {
+    let hgatp: usize;
+    unsafe {
+        asm!(
+            "csrr  {old}, 0x680", // 0x680 => hgatp
+            "or    {new}, {old}, {vmid}",
+            "csrw  0x680, {new}",
+            "csrr  {hgatp}, 0x680",
+            "csrw  0x680, {old}",
+            old = out(reg) _,
+            new = out(reg) _,
+            vmid = in(reg) HGATP_VMID_MASK << HGATP_VMID_SHIFT,
+            hgatp = out(reg) hgatp,
+            options(nomem, nostack),
+        );
+        core::arch::riscv64::hfence_gvma_all();
+    }
+    (hgatp >> HGATP_VMID_SHIFT & HGATP_VMID_MASK).count_ones() as usize
 }
 
 // Tries to execute all instructions defined in clojure `f`.
-// If resulted in an exception, this function returns its exception id.
+// If resulted in an exception, this function records it in `fault`.
 //
 // This function is useful to detect if an instruction exists on current environment.
 #[inline]
-fn with_detect_trap(param: usize, f: impl FnOnce()) -> usize {
+fn with_detect_trap(fault: &mut ProbeFault, f: impl FnOnce()) {
     // disable interrupts and handle exceptions only
-    let (sie, stvec, tp) = unsafe { init_detect_trap(param) };
+    let (sie, stvec, tp) = unsafe { init_detect_trap(fault as *mut ProbeFault as usize) };
     // run detection inner
     f();
     // restore trap handler and enable interrupts
@@ -40,45 +185,71 @@ fn with_detect_trap(param: usize, f: impl FnOnce()) -> usize {
 
 // rust trap handler for detect exceptions
 extern "C" fn rust_detect_trap(trap_frame: &mut TrapFrame) {
-    // store returned exception id value into tp register
-    // specially: illegal instruction => 2
-    trap_frame.tp = trap_frame.scause.bits();
-    // if illegal instruction, skip current instruction
+    // tp points to the fault record of the prober
+    let fault = unsafe { &mut *(trap_frame.tp as *mut ProbeFault) };
+    let cause_bit = 1usize
+        .checked_shl(trap_frame.scause.code() as u32)
+        .unwrap_or(0);
+    fault.scause = trap_frame.scause.bits();
+    fault.stval = trap_frame.stval;
+    fault.htval = if cause_bit & GUEST_PAGE_FAULTS != 0 {
+        htval::read()
+    } else {
+        0
+    };
     match trap_frame.scause.cause() {
-        Trap::Exception(Exception::IllegalInstruction) => {
-            let mut insn_bits = riscv_illegal_insn_bits((trap_frame.stval & 0xFFFF) as u16);
-            if insn_bits == 0 {
-                let insn_half = unsafe { *(trap_frame.sepc as *const u16) };
-                insn_bits = riscv_illegal_insn_bits(insn_half);
-            }
+        Trap::Exception(_) if cause_bit & FETCH_EXCEPTIONS != 0 => {
+            panic!(
+                "Instruction fetch fault while probing at {:#x}, scause {:#x}",
+                trap_frame.sepc,
+                trap_frame.scause.bits()
+            );
+        }
+        Trap::Exception(exception) => {
+            // the low bits of an illegal instruction are in stval, if provided
+            let stval_half = (trap_frame.stval & 0xFFFF) as u16;
+            let insn_half = if exception == Exception::IllegalInstruction && stval_half != 0 {
+                stval_half
+            } else {
+                unsafe { *(trap_frame.sepc as *const u16) }
+            };
+            let Some(insn_len) = riscv_insn_len(insn_half) else {
+                panic!(
+                    "Unknown length of instruction {insn_half:#06x} at {:#x} while probing",
+                    trap_frame.sepc
+                );
+            };
             // skip current instruction
-            trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_bits);
+            trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_len);
         }
-        Trap::Exception(_) => unreachable!(), // FIXME: unexpected instruction errors
         Trap::Interrupt(_) => unreachable!(), // filtered out for sie == false
     }
 }
 
-// Gets risc-v instruction bits from illegal instruction stval value, or 0 if unknown
+// Gets the length in bytes of a risc-v instruction from its lowest 16 bits, or `None` for the
+// reserved encodings of 192 bits and longer
 #[inline]
-fn riscv_illegal_insn_bits(insn: u16) -> usize {
-    if insn == 0 {
-        return 0; // stval[0..16] == 0, unknown
-    }
+fn riscv_insn_len(insn: u16) -> Option<usize> {
     if insn & 0b11 != 0b11 {
-        return 2; // 16-bit
+        return Some(2); // 16-bit
     }
     if insn & 0b11100 != 0b11100 {
-        return 4; // 32-bit
+        return Some(4); // 32-bit
+    }
+    if insn & 0b10_0000 == 0 {
+        return Some(6); // 48-bit, xxxxxxxxxx011111
+    }
+    if insn & 0b100_0000 == 0 {
+        return Some(8); // 64-bit, xxxxxxxxx0111111
     }
-    // FIXME: add >= 48-bit instructions in the future if we need to detect such instrucions
-    // >= 48-bit, unknown from this function by now
-    0
+    // (80 + 16 * nnn)-bit, xnnnxxxxx1111111
+    let nnn = (insn >> 12 & 0b111) as usize;
+    (nnn != 0b111).then_some(10 + 2 * nnn)
 }
 
 // Initialize environment for trap detection and filter in exception only
 #[inline]
-unsafe fn init_detect_trap(param: usize) -> (bool, Stvec, usize) {
+unsafe fn init_detect_trap(fault: usize) -> (bool, Stvec, usize) {
     // clear SIE to handle exception only
     let stored_sie = sstatus::read().sie();
     unsafe {
@@ -93,8 +264,9 @@ unsafe fn init_detect_trap(param: usize) -> (bool, Stvec, usize) {
     let stored_tp: usize;
     unsafe {
         stvec::write(trap_addr, TrapMode::Direct);
-        // store tp register. tp will be used to load parameter and store return value
-        asm!("mv  {}, tp", "mv  tp, {}", out(reg) stored_tp, in(reg) param, options(nomem, nostack));
+        // store tp register. tp will point to the fault record for the trap handler
+        // not `nomem`: the trap handler writes to the fault record
+        asm!("mv  {}, tp", "mv  tp, {}", out(reg) stored_tp, in(reg) fault, options(nostack));
     }
     // returns preserved previous hardware states
     (stored_sie, stored_stvec, stored_tp)
@@ -102,11 +274,10 @@ unsafe fn init_detect_trap(param: usize) -> (bool, Stvec, usize) {
 
 // Restore previous hardware states before trap detection
 #[inline]
-unsafe fn restore_detect_trap(sie: bool, stvec: Stvec, tp: usize) -> usize {
-    // read the return value from tp register, and restore tp value
-    let ans: usize;
+unsafe fn restore_detect_trap(sie: bool, stvec: Stvec, tp: usize) {
     unsafe {
-        asm!("mv  {}, tp", "mv  tp, {}", out(reg) ans, in(reg) tp, options(nomem, nostack));
+        // restore tp value
+        asm!("mv  tp, {}", in(reg) tp, options(nostack));
         // restore trap vector settings
         asm!("csrw  stvec, {}", in(reg) stvec.bits(), options(nomem, nostack));
         // enable interrupts
@@ -114,7 +285,6 @@ unsafe fn restore_detect_trap(sie: bool, stvec: Stvec, tp: usize) -> usize {
             sstatus::set_sie();
         };
     }
-    ans
 }
 
 // Trap frame for instruction exception detection
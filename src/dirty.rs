@@ -0,0 +1,192 @@
+//! Tracking of the guest pages written by a VM, for live migration.
+//!
+//! In [`DirtyLogMode::WriteProtect`] mode, the logged pages are write-protected in the G-stage
+//! page table, and marked with [`PTE_LOG_WP`]. The first write to such a page by any vCPU of the
+//! VM faults, and the page is made writable again without exiting to the VMM. A logged page that
+//! is writable has thus been written since the last harvest. In [`DirtyLogMode::HardwareDirty`]
+//! mode, the hardware sets the `D` bit of the G-stage PTEs (Svadu), which is harvested instead.
+//!
+//! All the state of the log is in the G-stage page table shared by the vCPUs of the VM, so the
+//! log is held by the VMM for the whole VM rather than by one of its vCPUs.
+//!
+//! [`PTE_LOG_WP`] is one of the bits reserved for software (RSW) in the PTEs, which must not be
+//! used otherwise in the logged range: [`DirtyLog::new`] fails if it is already set, e.g. by
+//! another log of the range. axaddrspace does not use the RSW bits, but clears them when it remaps
+//! a page: a page remapped writable is then reported by the next harvest, as if it was written,
+//! and a page remapped read-only is left read-only when the log is dropped, its writes being
+//! reported once it is made writable again.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err};
+use page_table_entry::riscv::PTEFlags;
+
+use crate::isa::HostIsaFeatures;
+use crate::page_walk::{GStageMode, gstage_leaf_pte};
+use crate::tlb;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// A reserved-for-software bit of the G-stage PTEs, set on writable pages write-protected for
+/// dirty logging.
+const PTE_LOG_WP: u64 = 1 << 8;
+
+/// How the pages written by the guest are detected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DirtyLogMode {
+    /// Write-protect the pages in the G-stage page table and handle the faults.
+    WriteProtect,
+    /// Harvest the `D` bit of the G-stage PTEs, set by the hardware. Requires Svadu with hardware
+    /// updating of the G-stage `A`/`D` bits enabled by the firmware (`menvcfg.ADUE`).
+    HardwareDirty,
+}
+
+/// The dirty log of a VM, covering a range of its guest physical memory.
+///
+/// Logging stops when the log is dropped, and the write-protected pages are made writable again.
+pub struct DirtyLog<M: AxMmHal> {
+    mode: DirtyLogMode,
+    hgatp: usize,
+    start: usize,
+    size: usize,
+    _marker: PhantomData<M>,
+}
+
+impl<M: AxMmHal> DirtyLog<M> {
+    /// Starts logging the pages in `start..start + size` written by the VM with the G-stage root
+    /// page table `ept_root` in translation mode `gstage_mode`, as given to the vCPUs of the VM.
+    ///
+    /// The G-stage TLB entries of the range are invalidated on all harts. Fails in
+    /// [`DirtyLogMode::HardwareDirty`] mode if the current hart does not report Svadu, and in
+    /// [`DirtyLogMode::WriteProtect`] mode if pages of the range are already write-protected for
+    /// logging.
+    pub fn new(
+        mode: DirtyLogMode,
+        gstage_mode: GStageMode,
+        ept_root: HostPhysAddr,
+        start: GuestPhysAddr,
+        size: usize,
+    ) -> AxResult<Self> {
+        if start.as_usize() % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return ax_err!(InvalidInput, "dirty log range is not page aligned");
+        }
+        if mode == DirtyLogMode::HardwareDirty && !HostIsaFeatures::current().svadu {
+            return ax_err!(
+                Unsupported,
+                "hardware updating of the A/D bits is not enabled"
+            );
+        }
+        let log = Self {
+            mode,
+            hgatp: gstage_mode.hgatp(ept_root),
+            start: start.as_usize(),
+            size,
+            _marker: PhantomData,
+        };
+        if mode == DirtyLogMode::WriteProtect {
+            let mut logged = false;
+            log.for_each_leaf(|pte, _| logged |= pte.load(Ordering::Acquire) & PTE_LOG_WP != 0);
+            if logged {
+                // Leave the pages to the log protecting them.
+                core::mem::forget(log);
+                return ax_err!(AlreadyExists, "dirty log range is already logged");
+            }
+        }
+        log.for_each_leaf(|pte, _| {
+            let _ = match mode {
+                DirtyLogMode::WriteProtect => write_protect(pte),
+                DirtyLogMode::HardwareDirty => clear_dirty(pte),
+            };
+        });
+        log.flush();
+        Ok(log)
+    }
+
+    /// Calls `f` with each page written by the VM since the last harvest, and tracks it again.
+    ///
+    /// The G-stage TLB entries of the range are invalidated on all harts, so that the writes to
+    /// the harvested pages are tracked again when this returns.
+    pub fn harvest(&self, mut f: impl FnMut(GuestPhysAddr)) {
+        self.for_each_leaf(|pte, pages| {
+            let written = match self.mode {
+                DirtyLogMode::WriteProtect => write_protect(pte),
+                DirtyLogMode::HardwareDirty => clear_dirty(pte),
+            };
+            // All pages of a superpage may have been written.
+            if written {
+                pages
+                    .step_by(PAGE_SIZE)
+                    .for_each(|gpa| f(GuestPhysAddr::from(gpa)));
+            }
+        });
+        self.flush();
+    }
+
+    /// Calls `f` with each leaf PTE mapping the logged range, and the range of guest physical
+    /// addresses it maps in the logged range.
+    fn for_each_leaf(&self, mut f: impl FnMut(&AtomicU64, core::ops::Range<usize>)) {
+        let end = self.start + self.size;
+        let mut gpa = self.start;
+        while gpa < end {
+            let Ok((pte, page_size)) = gstage_leaf_pte::<M>(self.hgatp, GuestPhysAddr::from(gpa))
+            else {
+                gpa += PAGE_SIZE;
+                continue;
+            };
+            let page_end = (gpa & !(page_size - 1)) + page_size;
+            f(pte, gpa..page_end.min(end));
+            gpa = page_end;
+        }
+    }
+
+    /// Invalidates the G-stage TLB entries of the logged range on all harts.
+    fn flush(&self) {
+        tlb::remote_flush_gstage(None, None, Some((self.start, self.size)));
+    }
+}
+
+impl<M: AxMmHal> Drop for DirtyLog<M> {
+    fn drop(&mut self) {
+        if self.mode == DirtyLogMode::WriteProtect {
+            self.for_each_leaf(|pte, _| {
+                let _ = pte.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pte| {
+                    (pte & PTE_LOG_WP != 0).then_some(pte & !PTE_LOG_WP | PTEFlags::W.bits() as u64)
+                });
+            });
+        }
+    }
+}
+
+/// Handles a guest write fault on `gpa` in the G-stage page table selected by `hgatp`. Returns
+/// whether it was caused by dirty logging, in which case the page is writable again and the
+/// guest can be resumed after invalidating the G-stage TLB entry of `gpa` on the current hart.
+///
+/// Called by any vCPU of the VM, whether the VMM logs its dirty pages or not.
+pub(crate) fn handle_write_fault<M: AxMmHal>(hgatp: usize, gpa: GuestPhysAddr) -> bool {
+    let Ok((pte, _)) = gstage_leaf_pte::<M>(hgatp, gpa) else {
+        return false;
+    };
+    let written = (PTEFlags::W | PTEFlags::A | PTEFlags::D).bits() as u64;
+    pte.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pte| {
+        // Another vCPU may have made the page writable, and this hart has a stale TLB entry.
+        (pte & (PTE_LOG_WP | PTEFlags::W.bits() as u64) != 0).then_some(pte & !PTE_LOG_WP | written)
+    })
+    .is_ok()
+}
+
+/// Write-protects the page mapped by `pte`. Returns whether it was writable, i.e. written since
+/// it was last write-protected.
+fn write_protect(pte: &AtomicU64) -> bool {
+    pte.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pte| {
+        (pte & PTEFlags::W.bits() as u64 != 0)
+            .then_some(pte & !(PTEFlags::W.bits() as u64) | PTE_LOG_WP)
+    })
+    .is_ok()
+}
+
+/// Clears the `D` bit of `pte`. Returns whether it was set.
+fn clear_dirty(pte: &AtomicU64) -> bool {
+    pte.fetch_and(!(PTEFlags::D.bits() as u64), Ordering::AcqRel) & PTEFlags::D.bits() as u64 != 0
+}
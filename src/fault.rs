@@ -0,0 +1,93 @@
+//! Decoding of guest-page faults taken from the guest.
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr, MappingFlags};
+
+use crate::consts::traps;
+use crate::regs::VmCpuTrapState;
+
+/// `GVA` bit in `hstatus`, set when `stval` holds a guest virtual address on a trap.
+const HSTATUS_GVA: usize = 1 << 6;
+
+/// `htinst` pseudo-instruction for an implicit 32-bit read of a VS-stage PTE.
+const HTINST_PT_READ_32: usize = 0x2000;
+/// `htinst` pseudo-instruction for an implicit 64-bit read of a VS-stage PTE.
+const HTINST_PT_READ_64: usize = 0x3000;
+/// `htinst` pseudo-instruction for an implicit 32-bit write of a VS-stage PTE (A/D bits update).
+const HTINST_PT_WRITE_32: usize = 0x2020;
+/// `htinst` pseudo-instruction for an implicit 64-bit write of a VS-stage PTE (A/D bits update).
+const HTINST_PT_WRITE_64: usize = 0x3020;
+
+/// What the guest was doing when a guest-page fault happened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuestPageFaultKind {
+    /// An explicit memory access of a guest instruction, or an instruction fetch.
+    Access,
+    /// An implicit access to a VS-stage page table entry while the hardware translated a guest
+    /// virtual address. `write` is set if the hardware was updating the A/D bits of the entry.
+    ///
+    /// These must not be emulated as MMIO: the page table page must be mapped, or an access fault
+    /// injected into the guest.
+    PageTableWalk {
+        /// Whether the implicit access is a write.
+        write: bool,
+    },
+}
+
+/// A guest-page fault, i.e. a fault in the G-stage address translation.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPageFault {
+    /// The faulting guest physical address. For page table walks, this is the address of the
+    /// VS-stage PTE.
+    pub gpa: GuestPhysAddr,
+    /// The kind of access that faulted.
+    pub access_flags: MappingFlags,
+    /// What the guest was doing.
+    pub kind: GuestPageFaultKind,
+    /// The guest virtual address being accessed or translated, if the hardware reported it.
+    pub gva: Option<GuestVirtAddr>,
+}
+
+impl GuestPageFault {
+    /// Decodes the guest-page fault described by `trap`, or returns `None` if the trap is not a
+    /// guest-page fault.
+    pub(crate) fn from_trap_state(trap: &VmCpuTrapState) -> Option<Self> {
+        if trap.scause >> (usize::BITS - 1) != 0 {
+            // Interrupt.
+            return None;
+        }
+        let access_flags = match 1usize.checked_shl(trap.scause as u32)? {
+            traps::exception::INST_GUEST_PAGE_FAULT => MappingFlags::EXECUTE,
+            traps::exception::LOAD_GUEST_PAGE_FAULT => MappingFlags::READ,
+            traps::exception::STORE_GUEST_PAGE_FAULT => MappingFlags::WRITE,
+            _ => return None,
+        };
+        // `htval` holds the guest physical address shifted right by 2 bits.
+        let pte_gpa = GuestPhysAddr::from(trap.htval << 2);
+        // For explicit accesses, the low bits are the same as the ones of the guest virtual
+        // address in `stval`.
+        let gpa = GuestPhysAddr::from(trap.htval << 2 | trap.stval & 0x3);
+        let gva = (trap.hstatus & HSTATUS_GVA != 0).then(|| GuestVirtAddr::from(trap.stval));
+
+        let fault = match trap.htinst {
+            HTINST_PT_READ_32 | HTINST_PT_READ_64 => Self {
+                gpa: pte_gpa,
+                access_flags: MappingFlags::READ,
+                kind: GuestPageFaultKind::PageTableWalk { write: false },
+                gva,
+            },
+            HTINST_PT_WRITE_32 | HTINST_PT_WRITE_64 => Self {
+                gpa: pte_gpa,
+                access_flags: MappingFlags::READ | MappingFlags::WRITE,
+                kind: GuestPageFaultKind::PageTableWalk { write: true },
+                gva,
+            },
+            _ => Self {
+                gpa,
+                access_flags,
+                kind: GuestPageFaultKind::Access,
+                gva,
+            },
+        };
+        Some(fault)
+    }
+}
@@ -0,0 +1,136 @@
+//! Access guest memory from HS-mode with the hypervisor virtual-machine load and store
+//! instructions (`hlv.*`, `hlvx.hu` and `hsv.*`).
+//!
+//! The accesses go through the guest's two-stage address translation currently installed on the
+//! hart (`vsatp` and `hgatp`), with the privilege given by `hstatus.SPVP`, which is set to the
+//! privilege the accesses are made with. Faults are caught by the trap-and-return procedure of
+//! [`crate::detect`], so an unmapped guest address results in a [`GuestMemFault`] instead of a
+//! crash.
+
+use core::arch::riscv64::{hlv_bu, hlvx_hu, hsv_b};
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
+
+use crate::consts::traps;
+use crate::detect::probe_instruction;
+
+/// `SPVP` bit in `hstatus`, the privilege of the hypervisor virtual-machine loads and stores.
+const HSTATUS_SPVP: usize = 1 << 8;
+
+/// A fault raised while accessing guest memory from the hypervisor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuestMemFault {
+    /// `scause` of the fault, e.g. a load guest-page fault.
+    pub scause: usize,
+    /// `stval` of the fault, the guest virtual address that faulted.
+    pub stval: usize,
+    /// `htval` of the fault, the guest physical address shifted right by 2 bits, or 0.
+    pub htval: usize,
+}
+
+impl GuestMemFault {
+    /// Returns the guest physical address of a guest-page fault, from `htval` and `stval`.
+    pub(crate) fn gpa(&self) -> GuestPhysAddr {
+        GuestPhysAddr::from(self.htval << 2 | self.stval & 0x3)
+    }
+}
+
+/// Result of a guest memory access.
+pub type GuestMemResult<T = ()> = Result<T, GuestMemFault>;
+
+// Runs a single guest memory access in `f`, and collects the fault information recorded by the
+// trap handler if it trapped.
+#[inline]
+fn guest_access(f: impl FnOnce()) -> GuestMemResult {
+    probe_instruction(f).map_err(|fault| GuestMemFault {
+        scause: fault.scause,
+        stval: fault.stval,
+        htval: fault.htval,
+    })
+}
+
+// Runs the guest memory accesses in `f` with the privilege of the guest's supervisor mode if
+// `supervisor` is set, or of its user mode otherwise.
+#[inline]
+fn with_privilege<T>(supervisor: bool, f: impl FnOnce() -> T) -> T {
+    let hstatus: usize;
+    unsafe {
+        core::arch::asm!("csrr {}, hstatus", out(reg) hstatus);
+        let spvp = if supervisor { HSTATUS_SPVP } else { 0 };
+        core::arch::asm!("csrw hstatus, {}", in(reg) hstatus & !HSTATUS_SPVP | spvp);
+    }
+    let ret = f();
+    unsafe {
+        core::arch::asm!("csrw hstatus, {}", in(reg) hstatus);
+    }
+    ret
+}
+
+/// Reads `buf.len()` bytes from guest virtual address `gva`, with the privilege of the guest's
+/// supervisor mode if `supervisor` is set, or of its user mode otherwise.
+pub(crate) fn read_guest_virt(
+    gva: GuestVirtAddr,
+    buf: &mut [u8],
+    supervisor: bool,
+) -> GuestMemResult {
+    with_privilege(supervisor, || {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let addr = (gva.as_usize() + i) as *const u8;
+            let mut val = 0;
+            guest_access(|| val = unsafe { hlv_bu(addr) })?;
+            *byte = val;
+        }
+        Ok(())
+    })
+}
+
+/// Writes `buf` to guest virtual address `gva`, with the privilege given as in
+/// [`read_guest_virt`].
+pub(crate) fn write_guest_virt(gva: GuestVirtAddr, buf: &[u8], supervisor: bool) -> GuestMemResult {
+    with_privilege(supervisor, || {
+        for (i, byte) in buf.iter().enumerate() {
+            let addr = (gva.as_usize() + i) as *mut i8;
+            guest_access(|| unsafe { hsv_b(addr, *byte as i8) })?;
+        }
+        Ok(())
+    })
+}
+
+/// Fetches the instruction at guest virtual address `gva`, with execute permission checks and the
+/// privilege given as in [`read_guest_virt`].
+///
+/// Returns the instruction bits and its length in bytes (2 or 4). Faults are reported as the
+/// instruction fetch faults the guest would get by executing the instruction.
+pub(crate) fn fetch_guest_insn(
+    gva: GuestVirtAddr,
+    supervisor: bool,
+) -> GuestMemResult<(u32, usize)> {
+    let addr = gva.as_usize();
+    with_privilege(supervisor, || {
+        let mut low = 0;
+        guest_access(|| low = unsafe { hlvx_hu(addr as *const u16) }).map_err(fetch_fault)?;
+        if low & 0b11 != 0b11 {
+            return Ok((low as u32, 2));
+        }
+        // The upper half may be on another page, so fetch it separately.
+        let mut high = 0;
+        guest_access(|| high = unsafe { hlvx_hu((addr + 2) as *const u16) })
+            .map_err(fetch_fault)?;
+        Ok(((high as u32) << 16 | low as u32, 4))
+    })
+}
+
+// Converts a fault of `hlvx`, which is reported as a load fault, to the corresponding instruction
+// fetch fault.
+fn fetch_fault(fault: GuestMemFault) -> GuestMemFault {
+    let scause = match 1usize.checked_shl(fault.scause as u32).unwrap_or(0) {
+        traps::exception::LOAD_ACCESS_FAULT => traps::exception::INST_ACCESSS_FAULT,
+        traps::exception::LOAD_PAGE_FAULT => traps::exception::INST_PAGE_FAULT,
+        traps::exception::LOAD_GUEST_PAGE_FAULT => traps::exception::INST_GUEST_PAGE_FAULT,
+        _ => return fault,
+    };
+    GuestMemFault {
+        scause: scause.trailing_zeros() as usize,
+        ..fault
+    }
+}
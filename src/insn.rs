@@ -0,0 +1,218 @@
+//! Decoding of guest load and store instructions, used to emulate guest memory accesses.
+//!
+//! Only integer loads and stores are decoded, including their compressed forms. Floating-point
+//! and atomic memory accesses are not emulated.
+
+use crate::regs::GprIndex;
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+
+/// The operation performed by a decoded load or store instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum MemOp {
+    /// A load into `rd`, sign-extended if `signed` is set.
+    Load { rd: GprIndex, signed: bool },
+    /// A store of the value of `rs2`.
+    Store { rs2: GprIndex },
+}
+
+/// A decoded load or store instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct MemInsn {
+    /// The operation.
+    pub op: MemOp,
+    /// The access width in bytes.
+    pub width: usize,
+    /// The length of the instruction in bytes.
+    pub len: usize,
+}
+
+impl MemOp {
+    fn load(rd: GprIndex, signed: bool) -> Self {
+        Self::Load { rd, signed }
+    }
+
+    fn store(rs2: GprIndex) -> Self {
+        Self::Store { rs2 }
+    }
+}
+
+impl MemInsn {
+    /// Decodes a raw instruction as fetched from guest memory. A compressed instruction is held
+    /// in the low 16 bits.
+    pub fn decode(insn: u32) -> Option<Self> {
+        if insn & 0b11 == 0b11 {
+            Self::decode_32(insn, 4)
+        } else {
+            Self::decode_16(insn as u16)
+        }
+    }
+
+    /// Decodes a transformed instruction as written to `htinst` on a trap.
+    ///
+    /// Transformed instructions always have the 32-bit format, with bit 1 cleared if the
+    /// original instruction was a compressed one.
+    pub fn decode_transformed(htinst: usize) -> Option<Self> {
+        let insn = htinst as u32;
+        if insn & 0b1 == 0 {
+            // Not a transformed instruction, e.g. a pseudo-instruction.
+            return None;
+        }
+        let len = if insn & 0b10 == 0 { 2 } else { 4 };
+        Self::decode_32(insn | 0b10, len)
+    }
+
+    fn decode_32(insn: u32, len: usize) -> Option<Self> {
+        let funct3 = (insn >> 12) & 0b111;
+        let rd = gpr((insn >> 7) & 0b1_1111);
+        let rs2 = gpr((insn >> 20) & 0b1_1111);
+        let (load, store) = (MemOp::load, MemOp::store);
+        let (op, width) = match (insn & 0b111_1111, funct3) {
+            (OPCODE_LOAD, 0b000) => (load(rd, true), 1),
+            (OPCODE_LOAD, 0b001) => (load(rd, true), 2),
+            (OPCODE_LOAD, 0b010) => (load(rd, true), 4),
+            (OPCODE_LOAD, 0b011) => (load(rd, false), 8),
+            (OPCODE_LOAD, 0b100) => (load(rd, false), 1),
+            (OPCODE_LOAD, 0b101) => (load(rd, false), 2),
+            (OPCODE_LOAD, 0b110) => (load(rd, false), 4),
+            (OPCODE_STORE, 0b000) => (store(rs2), 1),
+            (OPCODE_STORE, 0b001) => (store(rs2), 2),
+            (OPCODE_STORE, 0b010) => (store(rs2), 4),
+            (OPCODE_STORE, 0b011) => (store(rs2), 8),
+            _ => return None,
+        };
+        Some(Self { op, width, len })
+    }
+
+    fn decode_16(insn: u16) -> Option<Self> {
+        let insn = insn as u32;
+        let funct3 = (insn >> 13) & 0b111;
+        // `rd'`/`rs2'` of the CL/CS formats, mapped to x8-x15.
+        let rd_prime = gpr(((insn >> 2) & 0b111) + 8);
+        // `rd` of the CI format and `rs2` of the CSS format.
+        let rd = gpr((insn >> 7) & 0b1_1111);
+        let rs2 = gpr((insn >> 2) & 0b1_1111);
+        let (load, store) = (MemOp::load, MemOp::store);
+        let (op, width) = match (insn & 0b11, funct3) {
+            // C.LW, C.LD
+            (0b00, 0b010) => (load(rd_prime, true), 4),
+            (0b00, 0b011) => (load(rd_prime, false), 8),
+            // C.SW, C.SD
+            (0b00, 0b110) => (store(rd_prime), 4),
+            (0b00, 0b111) => (store(rd_prime), 8),
+            // C.LWSP, C.LDSP
+            (0b10, 0b010) if rd != GprIndex::Zero => (load(rd, true), 4),
+            (0b10, 0b011) if rd != GprIndex::Zero => (load(rd, false), 8),
+            // C.SWSP, C.SDSP
+            (0b10, 0b110) => (store(rs2), 4),
+            (0b10, 0b111) => (store(rs2), 8),
+            _ => return None,
+        };
+        Some(Self { op, width, len: 2 })
+    }
+}
+
+fn gpr(index: u32) -> GprIndex {
+    // Register fields are 5 bits wide, so this never fails.
+    GprIndex::from_raw(index).unwrap()
+}
+
+/// Truncates `val` to `width` bytes.
+pub(crate) fn truncate(val: usize, width: usize) -> usize {
+    if width * 8 >= usize::BITS as usize {
+        val
+    } else {
+        val & ((1 << (width * 8)) - 1)
+    }
+}
+
+/// Sign-extends the low `width` bytes of `val`.
+pub(crate) fn sign_extend(val: usize, width: usize) -> usize {
+    let unused_bits = usize::BITS as usize - width * 8;
+    ((val << unused_bits) as isize >> unused_bits) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insn(op: MemOp, width: usize, len: usize) -> Option<MemInsn> {
+        Some(MemInsn { op, width, len })
+    }
+
+    #[test]
+    fn decode_32() {
+        // lw a0, 8(sp)
+        assert_eq!(
+            MemInsn::decode(0x0081_2503),
+            insn(MemOp::load(GprIndex::A0, true), 4, 4)
+        );
+        // lbu t0, 0(a1)
+        assert_eq!(
+            MemInsn::decode(0x0005_c283),
+            insn(MemOp::load(GprIndex::T0, false), 1, 4)
+        );
+        // ld s1, 0(a0)
+        assert_eq!(
+            MemInsn::decode(0x0005_3483),
+            insn(MemOp::load(GprIndex::S1, false), 8, 4)
+        );
+        // sd a1, 16(sp)
+        assert_eq!(
+            MemInsn::decode(0x00b1_3823),
+            insn(MemOp::store(GprIndex::A1), 8, 4)
+        );
+        // addi a0, a0, 1
+        assert_eq!(MemInsn::decode(0x0015_0513), None);
+    }
+
+    #[test]
+    fn decode_16() {
+        // c.lw a0, 0(a1)
+        assert_eq!(
+            MemInsn::decode(0x4188),
+            insn(MemOp::load(GprIndex::A0, true), 4, 2)
+        );
+        // c.sd s1, 0(a0)
+        assert_eq!(
+            MemInsn::decode(0xe104),
+            insn(MemOp::store(GprIndex::S1), 8, 2)
+        );
+        // c.ldsp ra, 0(sp)
+        assert_eq!(
+            MemInsn::decode(0x6082),
+            insn(MemOp::load(GprIndex::RA, false), 8, 2)
+        );
+        // c.swsp a0, 0(sp)
+        assert_eq!(
+            MemInsn::decode(0xc02a),
+            insn(MemOp::store(GprIndex::A0), 4, 2)
+        );
+        // c.lwsp with rd = x0 is reserved.
+        assert_eq!(MemInsn::decode(0x4002), None);
+    }
+
+    #[test]
+    fn decode_transformed() {
+        // lw a0, with the address fields cleared.
+        assert_eq!(
+            MemInsn::decode_transformed(0x2503),
+            insn(MemOp::load(GprIndex::A0, true), 4, 4)
+        );
+        // The same, from a compressed instruction.
+        assert_eq!(
+            MemInsn::decode_transformed(0x2501),
+            insn(MemOp::load(GprIndex::A0, true), 4, 2)
+        );
+        assert_eq!(MemInsn::decode_transformed(0), None);
+    }
+
+    #[test]
+    fn extend() {
+        assert_eq!(truncate(0x1234, 1), 0x34);
+        assert_eq!(truncate(usize::MAX, 8), usize::MAX);
+        assert_eq!(sign_extend(0x80, 1), !0x7f);
+        assert_eq!(sign_extend(0x7fff, 2), 0x7fff);
+    }
+}
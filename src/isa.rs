@@ -0,0 +1,523 @@
+//! ISA extensions of the host harts relevant to virtualization.
+//!
+//! The extensions are either probed by the trap-and-return procedure, or parsed from the
+//! `riscv,isa` or `riscv,isa-extensions` properties of the CPU nodes of the host device tree. Some
+//! extensions, such as Zba/Zbb or Svpbmt, have no side effect observable from S-mode and are only
+//! known from the device tree.
+
+use core::arch::asm;
+
+use crate::detect::{
+    detect_gstage_mode, detect_h_extension, detect_ssaia, detect_svinval, detect_vmid_bits,
+    probe_instruction,
+};
+use crate::page_walk::GStageMode;
+use crate::percpu::HartState;
+
+/// `FS` field of `sstatus`, which must not be `Off` while probing the F and D extensions.
+const SSTATUS_FS: usize = 0b11 << 13;
+/// `VS` field of `sstatus`, which must not be `Off` while probing the V extension.
+const SSTATUS_VS: usize = 0b11 << 9;
+/// `ADUE` bit of `henvcfg`, writable if hardware updating of the `A`/`D` bits is enabled.
+const HENVCFG_ADUE: usize = 1 << 61;
+
+/// The ISA extensions implemented by a host hart.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HostIsaFeatures {
+    /// Hypervisor extension.
+    pub h: bool,
+    /// Supervisor-mode timer interrupts (`stimecmp`).
+    pub sstc: bool,
+    /// Supervisor-level Advanced Interrupt Architecture (`stopei`).
+    pub ssaia: bool,
+    /// Fine-grained address-translation cache invalidation.
+    pub svinval: bool,
+    /// Cache-block management instructions. Only reported if enabled for S-mode by the firmware.
+    pub zicbom: bool,
+    /// Cache-block zero instructions. Only reported if enabled for S-mode by the firmware.
+    pub zicboz: bool,
+    /// Single-precision floating-point.
+    pub f: bool,
+    /// Double-precision floating-point.
+    pub d: bool,
+    /// Vector extension.
+    pub v: bool,
+    /// The length of the vector registers in bytes (`vlenb`), 0 without the V extension.
+    pub vlenb: usize,
+    /// Count overflow and mode-based filtering of the performance counters (`scountovf`).
+    pub sscofpmf: bool,
+    /// State enable registers (`sstateen0`). Only reported if enabled for S-mode by the firmware.
+    pub smstateen: bool,
+    /// Entropy source (`seed`). Only reported if enabled for S-mode by the firmware.
+    pub zkr: bool,
+    /// `Sv39x4` G-stage translation mode.
+    pub sv39x4: bool,
+    /// `Sv48x4` G-stage translation mode.
+    pub sv48x4: bool,
+    /// `Sv57x4` G-stage translation mode.
+    pub sv57x4: bool,
+    /// The number of VMID bits in `hgatp`.
+    pub vmid_bits: usize,
+    /// Address generation instructions. Only known from the device tree.
+    pub zba: bool,
+    /// Basic bit manipulation instructions. Only known from the device tree.
+    pub zbb: bool,
+    /// Single-bit instructions. Only known from the device tree.
+    pub zbs: bool,
+    /// Page-based memory types. Only known from the device tree.
+    pub svpbmt: bool,
+    /// NAPOT translation contiguity. Only known from the device tree.
+    pub svnapot: bool,
+    /// Hardware updating of the `A`/`D` bits. Only reported if enabled by the firmware
+    /// (`menvcfg.ADUE`).
+    pub svadu: bool,
+}
+
+/// An extension on which the device tree and the probes of a hart disagree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IsaMismatch {
+    /// The name of the extension, as in the ISA string.
+    pub extension: &'static str,
+    /// Whether the extension is listed in the device tree.
+    pub described: bool,
+    /// Whether the extension was detected by the probes.
+    pub probed: bool,
+}
+
+/// An extension reported in [`HostIsaFeatures`].
+struct Extension {
+    /// The name of the extension, as in the ISA string.
+    name: &'static str,
+    /// Returns the flag of the extension.
+    flag: fn(&mut HostIsaFeatures) -> &mut bool,
+    /// Whether the extension is detected by [`HostIsaFeatures::detect`].
+    probed: bool,
+}
+
+const fn ext(
+    name: &'static str,
+    flag: fn(&mut HostIsaFeatures) -> &mut bool,
+    probed: bool,
+) -> Extension {
+    Extension { name, flag, probed }
+}
+
+const EXTENSIONS: &[Extension] = &[
+    ext("h", |f| &mut f.h, true),
+    ext("sstc", |f| &mut f.sstc, true),
+    ext("ssaia", |f| &mut f.ssaia, true),
+    ext("svinval", |f| &mut f.svinval, true),
+    ext("zicbom", |f| &mut f.zicbom, true),
+    ext("zicboz", |f| &mut f.zicboz, true),
+    ext("f", |f| &mut f.f, true),
+    ext("d", |f| &mut f.d, true),
+    ext("v", |f| &mut f.v, true),
+    ext("sscofpmf", |f| &mut f.sscofpmf, true),
+    ext("smstateen", |f| &mut f.smstateen, true),
+    ext("zkr", |f| &mut f.zkr, true),
+    ext("zba", |f| &mut f.zba, false),
+    ext("zbb", |f| &mut f.zbb, false),
+    ext("zbs", |f| &mut f.zbs, false),
+    ext("svpbmt", |f| &mut f.svpbmt, false),
+    ext("svnapot", |f| &mut f.svnapot, false),
+    ext("svadu", |f| &mut f.svadu, true),
+];
+
+impl HostIsaFeatures {
+    /// No extension.
+    pub(crate) const NONE: Self = Self {
+        h: false,
+        sstc: false,
+        ssaia: false,
+        svinval: false,
+        zicbom: false,
+        zicboz: false,
+        f: false,
+        d: false,
+        v: false,
+        vlenb: 0,
+        sscofpmf: false,
+        smstateen: false,
+        zkr: false,
+        sv39x4: false,
+        sv48x4: false,
+        sv57x4: false,
+        vmid_bits: 0,
+        zba: false,
+        zbb: false,
+        zbs: false,
+        svpbmt: false,
+        svnapot: false,
+        svadu: false,
+    };
+
+    /// Returns the ISA extensions of the current hart, [detected](Self::detect) once when
+    /// virtualization is enabled on it by [`RISCVPerCpu`](crate::RISCVPerCpu).
+    ///
+    /// Used for all feature decisions of the crate, so that the probes, which clobber the G-stage
+    /// TLB, run once per hart. Harts without virtualization enabled detect their features on each
+    /// call. The hart is given by [`RISCVVCpuHostIf::current_hart_id`](crate::RISCVVCpuHostIf).
+    pub fn current() -> Self {
+        match HartState::current() {
+            Some(state) => state.features(),
+            None => Self::detect(),
+        }
+    }
+
+    /// Detects the ISA extensions of the current hart by the trap-and-return procedure.
+    ///
+    /// Clobbers the TLB of the G-stage, as `hgatp` is probed.
+    pub fn detect() -> Self {
+        let mut features = Self {
+            h: detect_h_extension(),
+            sstc: probe(|| unsafe {
+                asm!("csrr {}, 0x14d", out(reg) _, options(nomem, nostack)); // 0x14d => stimecmp
+            }),
+            ssaia: detect_ssaia(),
+            svinval: detect_svinval(),
+            zicbom: probe_cbo(|block| unsafe {
+                asm!(".insn i 0x0f, 2, x0, 1({})", in(reg) block, options(nostack)); // cbo.clean
+            }),
+            zicboz: probe_cbo(|block| unsafe {
+                asm!(".insn i 0x0f, 2, x0, 4({})", in(reg) block, options(nostack)); // cbo.zero
+            }),
+            sscofpmf: probe(|| unsafe {
+                asm!("csrr {}, 0xda0", out(reg) _, options(nomem, nostack)); // 0xda0 => scountovf
+            }),
+            smstateen: probe(|| unsafe {
+                asm!("csrr {}, 0x10c", out(reg) _, options(nomem, nostack)); // 0x10c => sstateen0
+            }),
+            // `seed` must be accessed with a read-write instruction.
+            zkr: probe(|| unsafe {
+                asm!("csrrw {}, 0x015, zero", out(reg) _, options(nomem, nostack)); // 0x015 => seed
+            }),
+            ..Default::default()
+        };
+        with_sstatus_field(SSTATUS_FS, || {
+            features.f = probe(|| unsafe {
+                asm!("csrr {}, 0x003", out(reg) _, options(nomem, nostack)); // 0x003 => fcsr
+            });
+            // `fsgnj.d f0, f0, f0` leaves `f0` unchanged.
+            features.d = probe(|| unsafe {
+                asm!(".word 0x22000053", options(nomem, nostack));
+            });
+        });
+        with_sstatus_field(SSTATUS_VS, || {
+            let mut vlenb = 0;
+            features.v = probe(|| unsafe {
+                asm!("csrr {}, 0xc22", out(reg) vlenb, options(nomem, nostack)); // 0xc22 => vlenb
+            });
+            features.vlenb = if features.v { vlenb } else { 0 };
+        });
+        if features.h {
+            features.sv39x4 = detect_gstage_mode(GStageMode::Sv39x4);
+            features.sv48x4 = detect_gstage_mode(GStageMode::Sv48x4);
+            features.sv57x4 = detect_gstage_mode(GStageMode::Sv57x4);
+            features.vmid_bits = detect_vmid_bits();
+            features.svadu = probe_svadu();
+        }
+        features
+    }
+
+    /// Parses the `riscv,isa` property of a CPU node of the device tree, e.g.
+    /// `rv64imafdch_zicbom_zba_zbb_svpbmt`.
+    ///
+    /// Only the extensions are parsed: the G-stage modes, VMID bits and `vlenb` are not described
+    /// by the device tree. Unknown extensions and version numbers are ignored.
+    pub fn from_isa_string(isa: &str) -> Self {
+        let isa = isa.trim_end_matches('\0');
+        let isa = isa
+            .strip_prefix("rv64")
+            .or_else(|| isa.strip_prefix("rv32"))
+            .unwrap_or(isa);
+        // Single-letter extensions come first, until the first multi-letter one.
+        let multi = isa
+            .find(|c| matches!(c, '_' | 's' | 'z' | 'x'))
+            .unwrap_or(isa.len());
+        let (single, multi) = isa.split_at(multi);
+        let mut features = Self::default();
+        let mut prev = ' ';
+        for c in single.chars() {
+            // Skip version numbers, e.g. `i2p1`.
+            let version = c.is_ascii_digit() || (c == 'p' && prev.is_ascii_digit());
+            prev = c;
+            if version {
+                continue;
+            }
+            // `g` is `imafd_zicsr_zifencei`.
+            if c == 'g' {
+                features.f = true;
+                features.d = true;
+            } else {
+                features.set(c.encode_utf8(&mut [0; 4]));
+            }
+        }
+        for name in multi.split('_').filter(|name| !name.is_empty()) {
+            features.set(strip_version(name));
+        }
+        features
+    }
+
+    /// Parses the `riscv,isa-extensions` property of a CPU node of the device tree, a list of
+    /// NUL-terminated extension names.
+    ///
+    /// Only the extensions are parsed, as in [`from_isa_string`](Self::from_isa_string).
+    pub fn from_isa_extensions(extensions: &[u8]) -> Self {
+        let mut features = Self::default();
+        for name in extensions.split(|&b| b == 0) {
+            if let Ok(name) = core::str::from_utf8(name) {
+                features.set(name);
+            }
+        }
+        features
+    }
+
+    /// Returns the extensions detectable by the probes on which `self`, parsed from the device
+    /// tree, and `probed` disagree.
+    ///
+    /// Extensions only reported if enabled for S-mode by the firmware mismatch if they are
+    /// implemented but not enabled.
+    pub fn mismatches<'a>(&'a self, probed: &'a Self) -> impl Iterator<Item = IsaMismatch> + 'a {
+        EXTENSIONS
+            .iter()
+            .filter(|ext| ext.probed)
+            .map(|ext| IsaMismatch {
+                extension: ext.name,
+                described: self.get(ext),
+                probed: probed.get(ext),
+            })
+            .filter(|mismatch| mismatch.described != mismatch.probed)
+    }
+
+    /// Combines `self`, parsed from the device tree, with the features `probed` on the same hart,
+    /// warning about each [mismatch](Self::mismatches).
+    ///
+    /// The probed value of the extensions detectable by the probes is used, as it is what the
+    /// hart actually allows S-mode to use. The other extensions are taken from the device tree.
+    pub fn cross_check(&self, probed: &Self) -> Self {
+        for mismatch in self.mismatches(probed) {
+            warn!(
+                "ISA extension {} is {} in the device tree but {} by the probes",
+                mismatch.extension,
+                if mismatch.described {
+                    "listed"
+                } else {
+                    "missing"
+                },
+                if mismatch.probed {
+                    "detected"
+                } else {
+                    "not detected"
+                },
+            );
+        }
+        let mut features = *probed;
+        for ext in EXTENSIONS.iter().filter(|ext| !ext.probed) {
+            *(ext.flag)(&mut features) = self.get(ext);
+        }
+        features
+    }
+
+    /// Returns the flag of extension `ext`.
+    fn get(&self, ext: &Extension) -> bool {
+        let mut features = *self;
+        *(ext.flag)(&mut features)
+    }
+
+    /// Sets the flag of the extension named `name`, if known.
+    fn set(&mut self, name: &str) {
+        if let Some(ext) = EXTENSIONS
+            .iter()
+            .find(|ext| ext.name.eq_ignore_ascii_case(name))
+        {
+            *(ext.flag)(self) = true;
+        }
+    }
+
+    /// Returns whether the G-stage translation mode `mode` is supported.
+    pub fn supports_gstage_mode(&self, mode: GStageMode) -> bool {
+        match mode {
+            GStageMode::Sv39x4 => self.sv39x4,
+            GStageMode::Sv48x4 => self.sv48x4,
+            GStageMode::Sv57x4 => self.sv57x4,
+        }
+    }
+}
+
+/// Strips the version number of a multi-letter extension, e.g. `zba1p0`.
+fn strip_version(name: &str) -> &str {
+    let major = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if major.len() == name.len() {
+        return name;
+    }
+    match major.strip_suffix('p') {
+        Some(rest) if rest.ends_with(|c: char| c.is_ascii_digit()) => {
+            rest.trim_end_matches(|c: char| c.is_ascii_digit())
+        }
+        _ => major,
+    }
+}
+
+/// Returns whether all instructions in `f` execute without exceptions.
+fn probe(f: impl FnOnce()) -> bool {
+    probe_instruction(f).is_ok()
+}
+
+/// Probes a cache-block operation `f` on a scratch block, which may be zeroed.
+fn probe_cbo(f: impl FnOnce(*mut u8)) -> bool {
+    // Larger than any cache block.
+    #[repr(align(4096))]
+    struct Block([u8; 4096]);
+    let mut block = Block([0; 4096]);
+    probe(|| f(block.0.as_mut_ptr()))
+}
+
+/// Runs `f` with `field` of `sstatus` set to `Dirty`, then restores it.
+/// Returns whether hardware updating of the `A`/`D` bits is enabled by the firmware, which makes
+/// `henvcfg.ADUE` writable.
+fn probe_svadu() -> bool {
+    let mut henvcfg = 0;
+    let probed = probe(|| unsafe {
+        asm!(
+            "csrrs {old}, 0x60a, {adue}", // 0x60a => henvcfg
+            "csrr  {new}, 0x60a",
+            "csrw  0x60a, {old}",
+            old = out(reg) _,
+            adue = in(reg) HENVCFG_ADUE,
+            new = out(reg) henvcfg,
+            options(nomem, nostack),
+        );
+    });
+    probed && henvcfg & HENVCFG_ADUE != 0
+}
+
+fn with_sstatus_field(field: usize, f: impl FnOnce()) {
+    let old: usize;
+    unsafe {
+        asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) field, options(nomem, nostack));
+    }
+    f();
+    unsafe {
+        asm!(
+            "csrc sstatus, {field}",
+            "csrs sstatus, {old}",
+            field = in(reg) field,
+            old = in(reg) old & field,
+            options(nomem, nostack),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isa_string_with_versions() {
+        assert_eq!(
+            HostIsaFeatures::from_isa_string("rv64i2p1_m2p0_zicsr"),
+            HostIsaFeatures::default()
+        );
+        assert_eq!(
+            HostIsaFeatures::from_isa_string(
+                "rv64i2p1_m2p0_a2p1_f2p2_d2p2_c2p0_h1p0_zicsr2p0_zba1p0"
+            ),
+            HostIsaFeatures {
+                h: true,
+                f: true,
+                d: true,
+                zba: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn isa_string_without_versions() {
+        assert_eq!(
+            HostIsaFeatures::from_isa_string("rv64imafdch_zicbom_zba_zbb_svpbmt"),
+            HostIsaFeatures {
+                h: true,
+                zicbom: true,
+                f: true,
+                d: true,
+                zba: true,
+                zbb: true,
+                svpbmt: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            HostIsaFeatures::from_isa_string("rv64gcv_sstc\0"),
+            HostIsaFeatures {
+                sstc: true,
+                f: true,
+                d: true,
+                v: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn isa_extensions() {
+        assert_eq!(
+            HostIsaFeatures::from_isa_extensions(b"i\0m\0a\0f\0d\0c\0h\0zicsr\0sstc\0svnapot\0"),
+            HostIsaFeatures {
+                h: true,
+                sstc: true,
+                f: true,
+                d: true,
+                svnapot: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn version_stripping() {
+        assert_eq!(strip_version("zba1p0"), "zba");
+        assert_eq!(strip_version("zicsr2"), "zicsr");
+        assert_eq!(strip_version("zicbom"), "zicbom");
+    }
+
+    #[test]
+    fn cross_check_prefers_probes() {
+        let described = HostIsaFeatures::from_isa_string("rv64imafdch_sstc_zba");
+        let probed = HostIsaFeatures {
+            h: true,
+            zicbom: true,
+            f: true,
+            d: true,
+            sv39x4: true,
+            vmid_bits: 14,
+            ..Default::default()
+        };
+        let mut mismatches = described.mismatches(&probed);
+        assert_eq!(
+            mismatches.next(),
+            Some(IsaMismatch {
+                extension: "sstc",
+                described: true,
+                probed: false,
+            })
+        );
+        assert_eq!(
+            mismatches.next(),
+            Some(IsaMismatch {
+                extension: "zicbom",
+                described: false,
+                probed: true,
+            })
+        );
+        assert_eq!(mismatches.next(), None);
+        // Extensions the probes cannot detect are taken from the device tree.
+        assert_eq!(
+            described.cross_check(&probed),
+            HostIsaFeatures {
+                zba: true,
+                ..probed
+            }
+        );
+    }
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(doc_cfg)]
 #![feature(riscv_ext_intrinsics)]
 #![doc = include_str!("../README.md")]
@@ -6,17 +6,46 @@
 #[macro_use]
 extern crate log;
 
+mod aia;
 mod consts;
 /// The Control and Status Registers (CSRs) for a RISC-V hypervisor.
 mod detect;
+mod dirty;
+mod fault;
+mod guest_mem;
+mod insn;
+mod isa;
+mod mmio;
+mod page_walk;
 mod percpu;
 mod regs;
+mod tlb;
 mod trap;
+mod vaplic;
+mod vclint;
 mod vcpu;
+mod virq;
+mod vmid;
+mod vplic;
 
-pub use self::percpu::RISCVPerCpu;
+pub use self::aia::take_guest_external_pending;
+pub use self::dirty::{DirtyLog, DirtyLogMode};
+pub use self::fault::{GuestPageFault, GuestPageFaultKind};
+pub use self::guest_mem::{GuestMemFault, GuestMemResult};
+pub use self::isa::{HostIsaFeatures, IsaMismatch};
+pub use self::mmio::MmioAccess;
+pub use self::page_walk::{GStageMode, GuestTranslation};
+pub use self::percpu::{RISCVPerCpu, TrapDelegation};
+pub use self::vaplic::{APLIC_IDC_SIZE, APLIC_MMIO_SIZE, VirtAplic};
+pub use self::vclint::{ACLINT_SSWI_MMIO_SIZE, CLINT_MMIO_SIZE, VirtAclintSswi, VirtClint};
 pub use self::vcpu::RISCVVCpu;
+pub use self::virq::{VirtInterrupt, VirtIrqSink};
+pub use self::vplic::{PLIC_MMIO_SIZE, VirtPlic};
 pub use detect::detect_h_extension as has_hardware_support;
+pub use detect::{
+    ProbeFault, detect_gstage_mode, detect_guest_external_lines, detect_ssaia, detect_svinval,
+    detect_vmid_bits, probe_instruction,
+};
 
 /// Extension ID for hypercall, defined by ourselves.
 /// `0x48`, `0x56`, `0x43` is "HVC" in ASCII.
@@ -24,6 +53,34 @@ pub use detect::detect_h_extension as has_hardware_support;
 /// Borrowed from the design of `eid_from_str` in [sbi-spec](https://github.com/rustsbi/rustsbi/blob/62ab2e498ca66cdf75ce049c9dbc2f1862874553/sbi-spec/src/lib.rs#L51)
 pub const EID_HVC: usize = 0x485643;
 
+/// The `vector` of [`AxVCpuExitReason::ExternalInterrupt`] when the vCPU exits because of
+/// [`RISCVVCpu::kick`].
+///
+/// The IPI of the kick may also carry host IPIs sent to the hart at the same time, so the VMM
+/// must process its IPI queue on this exit as on an exit of vector `S_SOFT`.
+///
+/// [`AxVCpuExitReason::ExternalInterrupt`]: axvcpu::AxVCpuExitReason::ExternalInterrupt
+pub const KICK_EXIT_VECTOR: usize = usize::MAX;
+
+/// The `vector` of [`AxVCpuExitReason::ExternalInterrupt`] when a guest interrupt file of a
+/// descheduled vCPU receives an interrupt. The files are in the `hgeip` field of
+/// [`RISCVVCpu::trap_state`].
+///
+/// [`AxVCpuExitReason::ExternalInterrupt`]: axvcpu::AxVCpuExitReason::ExternalInterrupt
+pub const GUEST_EXTERNAL_EXIT_VECTOR: usize = consts::traps::irq::S_GUEST_EXT;
+
+/// Interface the host must implement with [`crate_interface::impl_interface`].
+///
+/// Added in 0.2.0: hosts upgrading from 0.1 must implement it, or linking fails with an undefined
+/// `__RISCVVCpuHostIf_current_hart_id` symbol. Neither `axvcpu` nor the other interfaces of this
+/// crate give the hart ID of the current CPU, which [`RISCVVCpu::kick`] needs to interrupt the
+/// hart the vCPU runs on.
+#[crate_interface::def_interface]
+pub trait RISCVVCpuHostIf {
+    /// Returns the hart ID of the current physical CPU.
+    fn current_hart_id() -> usize;
+}
+
 /// Configuration for creating a new `RISCVVCpu`
 #[derive(Clone, Debug)]
 pub struct RISCVVCpuCreateConfig {
@@ -32,6 +89,17 @@ pub struct RISCVVCpuCreateConfig {
     /// The physical address of the device tree blob.
     /// Default to `0x9000_0000`.
     pub dtb_addr: axaddrspace::GuestPhysAddr,
+    /// Whether misaligned loads and stores of the guest are emulated by the hypervisor.
+    /// If not, the misaligned access exceptions are injected into the guest. Default to `true`.
+    ///
+    /// This is usually the same for all vCPUs of a VM.
+    pub emulate_misaligned: bool,
+    /// The translation mode of the G-stage page table given to `set_ept_root`, which must match
+    /// the page table format of the address space. Default to `Sv39x4`.
+    pub gstage_mode: GStageMode,
+    /// The trap delegation of the vCPU, applied when it is bound to a CPU. Default to `None`,
+    /// which uses the delegation of the CPU, see [`RISCVPerCpu::set_trap_delegation`].
+    pub trap_delegation: Option<TrapDelegation>,
 }
 
 impl Default for RISCVVCpuCreateConfig {
@@ -39,6 +107,22 @@ impl Default for RISCVVCpuCreateConfig {
         Self {
             hart_id: 0,
             dtb_addr: axaddrspace::GuestPhysAddr::from_usize(0x9000_0000),
+            emulate_misaligned: true,
+            gstage_mode: GStageMode::Sv39x4,
+            trap_delegation: None,
+        }
+    }
+}
+
+// The unit tests run as user programs, which never call into the host.
+#[cfg(test)]
+mod test_host {
+    struct TestHost;
+
+    #[crate_interface::impl_interface]
+    impl crate::RISCVVCpuHostIf for TestHost {
+        fn current_hart_id() -> usize {
+            0
         }
     }
 }
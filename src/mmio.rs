@@ -0,0 +1,51 @@
+//! Decoding of guest MMIO accesses from guest-page faults.
+
+use axaddrspace::GuestPhysAddr;
+
+use crate::insn::{MemInsn, MemOp, truncate};
+use crate::regs::GprIndex;
+
+/// A guest load or store to an emulated MMIO region, decoded from a guest-page fault.
+///
+/// Obtained with [`RISCVVCpu::decode_mmio_access`](crate::RISCVVCpu::decode_mmio_access) and
+/// completed with [`RISCVVCpu::complete_mmio_access`](crate::RISCVVCpu::complete_mmio_access).
+#[derive(Clone, Copy, Debug)]
+pub struct MmioAccess {
+    /// The guest physical address accessed.
+    pub addr: GuestPhysAddr,
+    /// The access width in bytes.
+    pub width: usize,
+    /// The value written by the guest, or `None` for a read.
+    pub write_data: Option<usize>,
+    insn: MemInsn,
+}
+
+impl MmioAccess {
+    /// Builds the access performed by `insn` at `addr`. `reg_val` reads a guest register.
+    pub(crate) fn new(
+        addr: GuestPhysAddr,
+        insn: MemInsn,
+        reg_val: impl FnOnce(GprIndex) -> usize,
+    ) -> Self {
+        let write_data = match insn.op {
+            MemOp::Load { .. } => None,
+            MemOp::Store { rs2 } => Some(truncate(reg_val(rs2), insn.width)),
+        };
+        Self {
+            addr,
+            width: insn.width,
+            write_data,
+            insn,
+        }
+    }
+
+    /// Returns whether the access is a write.
+    pub fn is_write(&self) -> bool {
+        self.write_data.is_some()
+    }
+
+    /// Returns the decoded instruction of the access.
+    pub(crate) fn insn(&self) -> &MemInsn {
+        &self.insn
+    }
+}
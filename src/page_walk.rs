@@ -0,0 +1,222 @@
+//! Software walker of the guest's two-stage page tables.
+//!
+//! The VS-stage tables (rooted at `vsatp`) live in guest physical memory, which is read by
+//! walking the G-stage tables (rooted at `hgatp`) and accessing the backing host memory through
+//! [`AxMmHal::phys_to_virt`]. Nothing here traps, so it can be used to inspect a guest that is not
+//! currently running on this hart.
+
+use core::sync::atomic::AtomicU64;
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, GuestVirtAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err};
+use page_table_entry::GenericPTE;
+use page_table_entry::riscv::{PTEFlags, Rv64PTE};
+
+const PAGE_SHIFT: usize = 12;
+const PTE_SIZE: usize = 8;
+
+/// `MODE` field shift in `vsatp` and `hgatp`.
+pub(crate) const ATP_MODE_SHIFT: usize = 60;
+/// `PPN` field mask in `vsatp` and `hgatp`.
+pub(crate) const ATP_PPN_MASK: usize = (1 << 44) - 1;
+/// `VMID` field shift in `hgatp`.
+pub(crate) const HGATP_VMID_SHIFT: usize = 44;
+/// `VMID` field mask in `hgatp`, after shifting.
+pub(crate) const HGATP_VMID_MASK: usize = (1 << 14) - 1;
+
+/// The translation mode of the G-stage page table, selected by `hgatp.MODE`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum GStageMode {
+    /// 41-bit guest physical addresses, with 3 levels.
+    #[default]
+    Sv39x4,
+    /// 50-bit guest physical addresses, with 4 levels.
+    Sv48x4,
+    /// 59-bit guest physical addresses, with 5 levels.
+    Sv57x4,
+}
+
+impl GStageMode {
+    /// Returns the value of `hgatp.MODE` for the mode.
+    pub const fn hgatp_mode(self) -> usize {
+        match self {
+            Self::Sv39x4 => 8,
+            Self::Sv48x4 => 9,
+            Self::Sv57x4 => 10,
+        }
+    }
+
+    /// Returns the `hgatp` value selecting the mode with root page table at `root`.
+    pub(crate) fn hgatp(self, root: HostPhysAddr) -> usize {
+        self.hgatp_mode() << ATP_MODE_SHIFT | root.as_usize() >> PAGE_SHIFT & ATP_PPN_MASK
+    }
+}
+
+/// The result of translating a guest virtual address.
+#[derive(Debug)]
+pub struct GuestTranslation {
+    /// The guest physical address.
+    pub gpa: GuestPhysAddr,
+    /// The permissions of the leaf PTE. All permissions are set if translation is disabled.
+    pub flags: PTEFlags,
+    /// The size of the page (or superpage) that maps the address.
+    pub page_size: usize,
+}
+
+/// Returns the number of levels of the page table selected by `MODE` in `vsatp`/`hgatp`, or
+/// `None` for `Bare` and reserved modes.
+fn atp_levels(atp: usize) -> Option<usize> {
+    match atp >> ATP_MODE_SHIFT {
+        8 => Some(3),  // Sv39 / Sv39x4
+        9 => Some(4),  // Sv48 / Sv48x4
+        10 => Some(5), // Sv57 / Sv57x4
+        _ => None,
+    }
+}
+
+/// Walks a page table rooted at physical address `root`.
+///
+/// `gstage` selects the G-stage format, whose root table has 2 additional index bits and whose
+/// leaves must be user pages, as all guest accesses are checked as U-mode ones at the G-stage.
+/// `read_pte` reads a PTE at a physical address of the walked stage.
+///
+/// Returns the physical address `addr` maps to, with the leaf PTE flags, the page size and the
+/// physical address of the leaf PTE.
+fn walk(
+    root: usize,
+    levels: usize,
+    gstage: bool,
+    addr: usize,
+    mut read_pte: impl FnMut(usize) -> AxResult<Rv64PTE>,
+) -> AxResult<(usize, PTEFlags, usize, usize)> {
+    let root_extra_bits = if gstage { 2 } else { 0 };
+    let mut table = root;
+    for level in (0..levels).rev() {
+        let shift = PAGE_SHIFT + level * 9;
+        let index_bits = if level == levels - 1 {
+            9 + root_extra_bits
+        } else {
+            9
+        };
+        let index = (addr >> shift) & ((1 << index_bits) - 1);
+        let pte_addr = table + index * PTE_SIZE;
+        let pte = read_pte(pte_addr)?;
+        let flags = PTEFlags::from_bits_truncate(pte.bits());
+        if !flags.contains(PTEFlags::V)
+            || (flags.contains(PTEFlags::W) && !flags.contains(PTEFlags::R))
+        {
+            return ax_err!(NotFound, "invalid guest PTE");
+        }
+        let next = pte.paddr().as_usize();
+        if flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X) {
+            if gstage && !flags.contains(PTEFlags::U) {
+                return ax_err!(NotFound, "G-stage leaf PTE without the U bit");
+            }
+            let page_size = 1 << shift;
+            if next & (page_size - 1) != 0 {
+                return ax_err!(NotFound, "misaligned guest superpage");
+            }
+            return Ok((next | (addr & (page_size - 1)), flags, page_size, pte_addr));
+        }
+        // A, D and U are reserved in non-leaf PTEs.
+        if flags.intersects(PTEFlags::A | PTEFlags::D | PTEFlags::U) {
+            return ax_err!(NotFound, "invalid guest non-leaf PTE");
+        }
+        table = next;
+    }
+    ax_err!(NotFound, "guest page table too deep")
+}
+
+/// Translates guest physical address `gpa` with the G-stage page table selected by `hgatp`.
+///
+/// Returns the host physical address, with the leaf PTE flags and the page size.
+pub(crate) fn translate_guest_phys<M: AxMmHal>(
+    hgatp: usize,
+    gpa: GuestPhysAddr,
+) -> AxResult<(HostPhysAddr, PTEFlags, usize)> {
+    let Some(levels) = atp_levels(hgatp) else {
+        return ax_err!(BadState, "G-stage translation is disabled");
+    };
+    let (hpa, flags, page_size, _) = walk_guest_phys::<M>(hgatp, levels, gpa)?;
+    Ok((HostPhysAddr::from(hpa), flags, page_size))
+}
+
+/// Returns the leaf PTE of the G-stage page table selected by `hgatp` that maps guest physical
+/// address `gpa`, with the size of the page it maps.
+///
+/// The PTE can be updated atomically, as the hardware may do with Svadu.
+pub(crate) fn gstage_leaf_pte<M: AxMmHal>(
+    hgatp: usize,
+    gpa: GuestPhysAddr,
+) -> AxResult<(&'static AtomicU64, usize)> {
+    let Some(levels) = atp_levels(hgatp) else {
+        return ax_err!(BadState, "G-stage translation is disabled");
+    };
+    let (_, _, page_size, pte_addr) = walk_guest_phys::<M>(hgatp, levels, gpa)?;
+    let vaddr = M::phys_to_virt(HostPhysAddr::from(pte_addr));
+    Ok((
+        unsafe { &*(vaddr.as_usize() as *const AtomicU64) },
+        page_size,
+    ))
+}
+
+fn walk_guest_phys<M: AxMmHal>(
+    hgatp: usize,
+    levels: usize,
+    gpa: GuestPhysAddr,
+) -> AxResult<(usize, PTEFlags, usize, usize)> {
+    // The guest physical address space is 2 bits wider than the virtual one of the same mode.
+    if gpa.as_usize() >> (PAGE_SHIFT + levels * 9 + 2) != 0 {
+        return ax_err!(InvalidInput, "guest physical address out of range");
+    }
+    let root = (hgatp & ATP_PPN_MASK) << PAGE_SHIFT;
+    walk(root, levels, true, gpa.as_usize(), |paddr| {
+        Ok(read_host_phys::<M>(HostPhysAddr::from(paddr)))
+    })
+}
+
+/// Translates guest virtual address `gva` with the VS-stage page table selected by `vsatp`,
+/// reading the guest page table through the G-stage page table selected by `hgatp`.
+pub(crate) fn translate_guest_virt<M: AxMmHal>(
+    hgatp: usize,
+    vsatp: usize,
+    gva: GuestVirtAddr,
+) -> AxResult<GuestTranslation> {
+    let gva = gva.as_usize();
+    let Some(levels) = atp_levels(vsatp) else {
+        if vsatp >> ATP_MODE_SHIFT != 0 {
+            return ax_err!(Unsupported, "unsupported VS-stage translation mode");
+        }
+        return Ok(GuestTranslation {
+            gpa: GuestPhysAddr::from(gva),
+            flags: PTEFlags::V
+                | PTEFlags::R
+                | PTEFlags::W
+                | PTEFlags::X
+                | PTEFlags::A
+                | PTEFlags::D,
+            page_size: 1 << PAGE_SHIFT,
+        });
+    };
+    // Virtual addresses must be sign-extended from the highest translated bit.
+    let unused_bits = usize::BITS as usize - (PAGE_SHIFT + levels * 9);
+    if ((gva as isize) << unused_bits >> unused_bits) as usize != gva {
+        return ax_err!(InvalidInput, "non-canonical guest virtual address");
+    }
+    let root = (vsatp & ATP_PPN_MASK) << PAGE_SHIFT;
+    let (gpa, flags, page_size, _) = walk(root, levels, false, gva, |pte_gpa| {
+        let (hpa, _, _) = translate_guest_phys::<M>(hgatp, GuestPhysAddr::from(pte_gpa))?;
+        Ok(read_host_phys::<M>(hpa))
+    })?;
+    Ok(GuestTranslation {
+        gpa: GuestPhysAddr::from(gpa),
+        flags,
+        page_size,
+    })
+}
+
+/// Reads a PTE in host physical memory.
+fn read_host_phys<M: AxMmHal>(paddr: HostPhysAddr) -> Rv64PTE {
+    let vaddr = M::phys_to_virt(paddr);
+    unsafe { (vaddr.as_usize() as *const Rv64PTE).read_volatile() }
+}
@@ -1,67 +1,295 @@
+use core::cell::UnsafeCell;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, ax_err};
 use axvcpu::{AxArchPerCpu, AxVCpuHal};
 use riscv::register::{hedeleg, hideleg, hvip, sie};
 
+use crate::RISCVVCpuHostIf;
+use crate::aia::HartGuestFiles;
 use crate::consts::traps;
-use crate::has_hardware_support;
+use crate::isa::HostIsaFeatures;
+use crate::vmid::HartVmids;
+
+/// Number of physical CPUs virtualization can be enabled on, whatever their hart IDs.
+const MAX_CPUS: usize = 256;
+
+/// The state shared by each physical CPU with the vCPUs bound to it, in the order the CPUs
+/// enabled virtualization.
+static HARTS: [HartState; MAX_CPUS] = [const { HartState::new() }; MAX_CPUS];
 
 /// Risc-V per-CPU state.
 pub struct RISCVPerCpu<H: AxVCpuHal> {
+    // The CSRs as they were before virtualization was enabled, or `None` if it is disabled.
+    saved_csrs: Option<HostCsrs>,
+    delegation: TrapDelegation,
+    // The ISA extensions of the CPU, detected when virtualization is first enabled.
+    features: HostIsaFeatures,
     _marker: PhantomData<H>,
 }
 
-impl<H: AxVCpuHal> AxArchPerCpu for RISCVPerCpu<H> {
-    fn new(_cpu_id: usize) -> AxResult<Self> {
+/// The traps of the guest delegated to it, i.e. handled by the guest without VM exits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TrapDelegation {
+    /// The delegated exceptions, in the layout of `hedeleg`.
+    pub exceptions: usize,
+    /// The delegated interrupts, in the layout of `hideleg`.
+    pub interrupts: usize,
+}
+
+impl Default for TrapDelegation {
+    fn default() -> Self {
+        Self {
+            // Delegate some synchronous exceptions.
+            exceptions: traps::exception::INST_ADDR_MISALIGN
+                | traps::exception::BREAKPOINT
+                | traps::exception::ENV_CALL_FROM_U_OR_VU
+                | traps::exception::INST_PAGE_FAULT
+                | traps::exception::LOAD_PAGE_FAULT
+                | traps::exception::STORE_PAGE_FAULT
+                | traps::exception::ILLEGAL_INST,
+            // Delegate all interupts.
+            interrupts: traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+                | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
+                | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+        }
+    }
+}
+
+impl TrapDelegation {
+    /// Reads the delegation of the current hart.
+    pub(crate) fn read() -> Self {
+        let (exceptions, interrupts): (usize, usize);
         unsafe {
-            setup_csrs();
+            core::arch::asm!("csrr {}, hedeleg", out(reg) exceptions);
+            core::arch::asm!("csrr {}, hideleg", out(reg) interrupts);
         }
+        Self {
+            exceptions,
+            interrupts,
+        }
+    }
+
+    /// Applies the delegation to the current hart.
+    pub(crate) unsafe fn write(&self) {
+        unsafe {
+            hedeleg::Hedeleg::from_bits(self.exceptions).write();
+            hideleg::Hideleg::from_bits(self.interrupts).write();
+        }
+    }
+}
+
+impl<H: AxVCpuHal> RISCVPerCpu<H> {
+    /// Returns the ISA extensions of the CPU, detected by
+    /// [`hardware_enable`](AxArchPerCpu::hardware_enable). The vCPUs bound to the CPU use the
+    /// same features, see [`HostIsaFeatures::current`].
+    pub fn isa_features(&self) -> &HostIsaFeatures {
+        &self.features
+    }
+
+    /// Sets the default trap delegation of the guests running on this CPU, used by vCPUs without
+    /// their own delegation.
+    ///
+    /// Applied immediately if virtualization is enabled, otherwise on
+    /// [`hardware_enable`](AxArchPerCpu::hardware_enable).
+    pub fn set_trap_delegation(&mut self, delegation: TrapDelegation) {
+        self.delegation = delegation;
+        if self.saved_csrs.is_some() {
+            unsafe {
+                delegation.write();
+            }
+        }
+    }
+}
+
+/// The state of a physical CPU shared with the vCPUs bound to it, found by hart ID.
+///
+/// Claimed by [`hardware_enable`](AxArchPerCpu::hardware_enable) and never released, so that
+/// `HARTS` is filled from the start.
+pub(crate) struct HartState {
+    // The hart ID plus one, or 0 if the state is free.
+    hart: AtomicUsize,
+    // Whether `features` is written.
+    ready: AtomicBool,
+    features: UnsafeCell<HostIsaFeatures>,
+    /// The guest interrupt files of the hart.
+    pub guest_files: HartGuestFiles,
+    /// The VMIDs allocated by the hart.
+    pub vmids: HartVmids,
+}
+
+// The features are written once, before `ready` is set, and only read afterwards.
+unsafe impl Sync for HartState {}
+
+impl HartState {
+    const fn new() -> Self {
+        Self {
+            hart: AtomicUsize::new(0),
+            ready: AtomicBool::new(false),
+            features: UnsafeCell::new(HostIsaFeatures::NONE),
+            guest_files: HartGuestFiles::new(),
+            vmids: HartVmids::new(),
+        }
+    }
+
+    /// Returns the state of the current hart, or `None` if virtualization was never enabled on
+    /// it.
+    pub fn current() -> Option<&'static Self> {
+        let hart = crate_interface::call_interface!(RISCVVCpuHostIf::current_hart_id);
+        Self::of(hart)
+    }
 
+    /// Returns the state of hart `hart`, or `None` if virtualization was never enabled on it.
+    pub fn of(hart: usize) -> Option<&'static Self> {
+        HARTS
+            .iter()
+            .take_while(|state| state.hart.load(Ordering::Acquire) != 0)
+            .find(|state| {
+                state.hart.load(Ordering::Relaxed) == hart + 1
+                    && state.ready.load(Ordering::Acquire)
+            })
+    }
+
+    /// Returns the states of the harts virtualization was enabled on.
+    pub fn all() -> impl Iterator<Item = &'static Self> {
+        HARTS
+            .iter()
+            .take_while(|state| state.hart.load(Ordering::Acquire) != 0)
+            .filter(|state| state.ready.load(Ordering::Acquire))
+    }
+
+    /// Returns the ID of the hart.
+    pub fn hart(&self) -> usize {
+        self.hart.load(Ordering::Relaxed) - 1
+    }
+
+    /// Returns the state of the current hart, claimed and filled with the detected features of
+    /// the hart on the first call.
+    fn register() -> AxResult<&'static Self> {
+        let hart = crate_interface::call_interface!(RISCVVCpuHostIf::current_hart_id);
+        if let Some(state) = Self::of(hart) {
+            return Ok(state);
+        }
+        let Some(state) = HARTS.iter().find(|state| {
+            state
+                .hart
+                .compare_exchange(0, hart + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        }) else {
+            return ax_err!(NoMemory, "too many CPUs to enable virtualization on");
+        };
+        unsafe { *state.features.get() = HostIsaFeatures::detect() };
+        state.ready.store(true, Ordering::Release);
+        Ok(state)
+    }
+
+    /// Returns the ISA extensions of the hart.
+    pub fn features(&self) -> HostIsaFeatures {
+        unsafe { *self.features.get() }
+    }
+}
+
+/// The (H)S-level CSRs configured by `setup_csrs`.
+#[derive(Debug)]
+struct HostCsrs {
+    hedeleg: usize,
+    hideleg: usize,
+    hcounteren: usize,
+    hvip: usize,
+    sie: usize,
+}
+
+impl HostCsrs {
+    /// Reads the CSRs of the current hart.
+    fn read() -> Self {
+        let (hedeleg, hideleg, hcounteren, hvip, sie): (usize, usize, usize, usize, usize);
+        unsafe {
+            core::arch::asm!(
+                "csrr {hedeleg}, hedeleg",
+                "csrr {hideleg}, hideleg",
+                "csrr {hcounteren}, 0x606", // 0x606 => hcounteren
+                "csrr {hvip}, hvip",
+                "csrr {sie}, sie",
+                hedeleg = out(reg) hedeleg,
+                hideleg = out(reg) hideleg,
+                hcounteren = out(reg) hcounteren,
+                hvip = out(reg) hvip,
+                sie = out(reg) sie,
+            );
+        }
+        Self {
+            hedeleg,
+            hideleg,
+            hcounteren,
+            hvip,
+            sie,
+        }
+    }
+
+    /// Writes the CSRs of the current hart.
+    unsafe fn write(&self) {
+        unsafe {
+            core::arch::asm!(
+                "csrw hedeleg, {hedeleg}",
+                "csrw hideleg, {hideleg}",
+                "csrw 0x606, {hcounteren}", // 0x606 => hcounteren
+                "csrw hvip, {hvip}",
+                "csrw sie, {sie}",
+                hedeleg = in(reg) self.hedeleg,
+                hideleg = in(reg) self.hideleg,
+                hcounteren = in(reg) self.hcounteren,
+                hvip = in(reg) self.hvip,
+                sie = in(reg) self.sie,
+            );
+        }
+    }
+}
+
+impl<H: AxVCpuHal> AxArchPerCpu for RISCVPerCpu<H> {
+    fn new(_cpu_id: usize) -> AxResult<Self> {
         Ok(Self {
+            saved_csrs: None,
+            delegation: TrapDelegation::default(),
+            features: HostIsaFeatures::default(),
             _marker: PhantomData,
         })
     }
 
     fn is_enabled(&self) -> bool {
-        unimplemented!()
+        self.saved_csrs.is_some()
     }
 
     fn hardware_enable(&mut self) -> AxResult<()> {
-        if has_hardware_support() {
-            Ok(())
-        } else {
-            Err(AxError::Unsupported)
+        // Detects the features on this CPU, and shares them with the vCPUs bound to it.
+        self.features = HartState::register()?.features();
+        if !self.features.h {
+            return Err(AxError::Unsupported);
+        }
+        if self.saved_csrs.is_none() {
+            self.saved_csrs = Some(HostCsrs::read());
+            unsafe {
+                setup_csrs(&self.delegation);
+            }
         }
+        Ok(())
     }
 
     fn hardware_disable(&mut self) -> AxResult<()> {
-        unimplemented!()
+        let Some(csrs) = self.saved_csrs.take() else {
+            return Err(AxError::BadState);
+        };
+        unsafe {
+            csrs.write();
+        }
+        Ok(())
     }
 }
 
 /// Initialize (H)S-level CSRs to a reasonable state.
-unsafe fn setup_csrs() {
+unsafe fn setup_csrs(delegation: &TrapDelegation) {
     unsafe {
-        // Delegate some synchronous exceptions.
-        hedeleg::Hedeleg::from_bits(
-            traps::exception::INST_ADDR_MISALIGN
-                | traps::exception::BREAKPOINT
-                | traps::exception::ENV_CALL_FROM_U_OR_VU
-                | traps::exception::INST_PAGE_FAULT
-                | traps::exception::LOAD_PAGE_FAULT
-                | traps::exception::STORE_PAGE_FAULT
-                | traps::exception::ILLEGAL_INST,
-        )
-        .write();
-
-        // Delegate all interupts.
-        hideleg::Hideleg::from_bits(
-            traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
-                | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL
-                | traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
-        )
-        .write();
+        delegation.write();
 
         // Clear all interrupts.
         hvip::clear_vssip();
@@ -77,5 +305,11 @@ unsafe fn setup_csrs() {
         sie::set_sext();
         sie::set_ssoft();
         sie::set_stimer();
+        // The guest interrupt files of descheduled vCPUs wake them through guest external
+        // interrupts, taken by the host outside `run`.
+        core::arch::asm!(
+            "csrs sie, {}",
+            in(reg) traps::interrupt::SUPERVISOR_GUEST_EXTERNEL,
+        );
     }
 }
@@ -151,6 +151,7 @@ pub struct GuestVsCsrs {
     pub vstval: usize,
     pub vsatp: usize,
     pub vstimecmp: usize,
+    pub vsiselect: usize,
 }
 
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
@@ -172,6 +173,9 @@ pub struct VmCpuTrapState {
     pub stval: usize,
     pub htval: usize,
     pub htinst: usize,
+    pub sepc: usize,
+    pub hgeip: usize,
+    pub hstatus: usize,
 }
 
 /// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
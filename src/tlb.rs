@@ -0,0 +1,112 @@
+//! Invalidation of the G-stage and VS-stage TLB entries of the current hart, or of all harts
+//! through the SBI RFENCE extension.
+//!
+//! Ranges are invalidated page by page, with `hinval.gvma`/`hinval.vvma` batched between
+//! `sfence.w.inval` and `sfence.inval.ir` when Svinval is supported. Large ranges are invalidated
+//! entirely instead.
+
+use core::arch::riscv64::{
+    hfence_gvma, hfence_gvma_vmid, hfence_vvma, hfence_vvma_all, hfence_vvma_asid,
+    hfence_vvma_vaddr, hinval_gvma, hinval_vvma, hinval_vvma_vaddr, sfence_inval_ir,
+    sfence_w_inval,
+};
+
+use sbi_spec::binary::HartMask;
+
+const PAGE_SIZE: usize = 0x1000;
+/// Number of pages above which a range is invalidated entirely.
+const MAX_RANGE_PAGES: usize = 64;
+
+/// Invalidates the G-stage translations of guest physical addresses `start..start + size` for
+/// `vmid`, or all of them if `range` is `None`.
+pub(crate) fn flush_gstage(vmid: usize, range: Option<(usize, usize)>, svinval: bool) {
+    let Some(pages) = range.and_then(pages) else {
+        unsafe { hfence_gvma_vmid(vmid) };
+        return;
+    };
+    // Guest physical addresses are given shifted right by 2 bits.
+    if svinval {
+        unsafe {
+            sfence_w_inval();
+            for gpa in pages {
+                hinval_gvma(gpa >> 2, vmid);
+            }
+            sfence_inval_ir();
+        }
+    } else {
+        for gpa in pages {
+            unsafe { hfence_gvma(gpa >> 2, vmid) };
+        }
+    }
+}
+
+/// Invalidates the G-stage translations of guest physical addresses `start..start + size` on
+/// hart `hart` or all harts if it is `None`, for `vmid` or all VMIDs if it is `None`, or all
+/// translations if `range` is `None`.
+pub(crate) fn remote_flush_gstage(
+    hart: Option<usize>,
+    vmid: Option<usize>,
+    range: Option<(usize, usize)>,
+) {
+    // A start address and size of 0 invalidate the whole address space.
+    let (start, size) = range.unwrap_or((0, 0));
+    let harts = match hart {
+        Some(hart) => HartMask::from_mask_base(1, hart),
+        None => HartMask::from_mask_base(0, usize::MAX),
+    };
+    let ret = match vmid {
+        Some(vmid) => sbi_rt::remote_hfence_gvma_vmid(harts, start, size, vmid),
+        None => sbi_rt::remote_hfence_gvma(harts, start, size),
+    };
+    if ret.is_err() {
+        warn!("Failed to invalidate G-stage translations of VMID {vmid:?}: {ret:?}");
+    }
+}
+
+/// Invalidates the VS-stage translations of guest virtual addresses `start..start + size` for
+/// `asid`, or all of them if `range` is `None`. All address spaces are invalidated if `asid` is
+/// `None`.
+///
+/// Only the translations of the VMID currently in `hgatp` are invalidated.
+pub(crate) fn flush_vsstage(asid: Option<usize>, range: Option<(usize, usize)>, svinval: bool) {
+    let Some(pages) = range.and_then(pages) else {
+        unsafe {
+            match asid {
+                Some(asid) => hfence_vvma_asid(asid),
+                None => hfence_vvma_all(),
+            }
+        }
+        return;
+    };
+    if svinval {
+        unsafe {
+            sfence_w_inval();
+            for gva in pages {
+                match asid {
+                    Some(asid) => hinval_vvma(gva, asid),
+                    None => hinval_vvma_vaddr(gva),
+                }
+            }
+            sfence_inval_ir();
+        }
+    } else {
+        for gva in pages {
+            unsafe {
+                match asid {
+                    Some(asid) => hfence_vvma(gva, asid),
+                    None => hfence_vvma_vaddr(gva),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the addresses of the pages in `start..start + size`, or `None` if there are too many
+/// of them.
+fn pages((start, size): (usize, usize)) -> Option<impl Iterator<Item = usize>> {
+    let first = start & !(PAGE_SIZE - 1);
+    let end = start
+        .checked_add(size)?
+        .checked_next_multiple_of(PAGE_SIZE)?;
+    ((end - first) / PAGE_SIZE <= MAX_RANGE_PAGES).then(|| (first..end).step_by(PAGE_SIZE))
+}
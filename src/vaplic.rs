@@ -0,0 +1,468 @@
+//! Emulated Advanced Platform-Level Interrupt Controller (APLIC) for guests.
+//!
+//! A single supervisor-level interrupt domain without child domains, with the register layout of
+//! the RISC-V AIA specification. Both delivery modes are supported: in direct mode, interrupts are
+//! delivered through the interrupt delivery control (IDC) structure of each hart, which drives the
+//! supervisor external interrupt of the vCPU; in MSI mode, interrupts are forwarded as MSIs with
+//! [`VirtIrqSink::send_msi`], usually written to the guest interrupt files of the vCPUs. Hart
+//! index `n` is the vCPU with ID `n`.
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::consts::traps::irq::MAX_IRQ_COUNT;
+use crate::mmio::MmioAccess;
+use crate::virq::{VirtInterrupt, VirtIrqSink};
+
+/// Size of the APLIC MMIO region, without the IDC structures.
+pub const APLIC_MMIO_SIZE: usize = IDC_BASE;
+/// Size of the IDC structure of each hart, following the APLIC MMIO region.
+pub const APLIC_IDC_SIZE: usize = 0x20;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const TARGET_BASE: usize = 0x3004;
+const IDC_BASE: usize = 0x4000;
+
+const IDC_IDELIVERY: usize = 0x00;
+const IDC_IFORCE: usize = 0x04;
+const IDC_ITHRESHOLD: usize = 0x08;
+const IDC_TOPI: usize = 0x18;
+const IDC_CLAIMI: usize = 0x1c;
+
+/// Read-only bits of `domaincfg`.
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+/// Interrupt enable bit of `domaincfg`.
+const DOMAINCFG_IE: u32 = 1 << 8;
+/// Delivery mode bit of `domaincfg`, set for MSI delivery mode.
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+/// Source mode field of `sourcecfg`.
+const SOURCECFG_SM_MASK: u32 = 0x7;
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE_RISE: u32 = 4;
+const SM_EDGE_FALL: u32 = 5;
+const SM_LEVEL_HIGH: u32 = 6;
+const SM_LEVEL_LOW: u32 = 7;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_GUEST_SHIFT: u32 = 12;
+const TARGET_GUEST_MASK: u32 = 0x3f;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+const TARGET_IPRIO_MASK: u32 = 0xff;
+
+/// Number of 32-bit words in a bitmap of all interrupt sources.
+const SOURCE_WORDS: usize = MAX_IRQ_COUNT / 32;
+
+/// An emulated APLIC domain with `HARTS` harts, one per vCPU.
+///
+/// Driven by guest MMIO accesses with [`Self::handle_mmio`], and by devices with
+/// [`Self::set_source_level`]. Interrupts are delivered through the [`VirtIrqSink`].
+pub struct VirtAplic<S: VirtIrqSink, const HARTS: usize> {
+    base: GuestPhysAddr,
+    num_sources: usize,
+    domaincfg: u32,
+    sourcecfg: [u32; MAX_IRQ_COUNT],
+    target: [u32; MAX_IRQ_COUNT],
+    pending: [u32; SOURCE_WORDS],
+    enabled: [u32; SOURCE_WORDS],
+    // Raw levels of the interrupt source inputs.
+    input: [u32; SOURCE_WORDS],
+    idelivery: [u32; HARTS],
+    iforce: [u32; HARTS],
+    ithreshold: [u32; HARTS],
+    // Current level of the external interrupt line of each hart, in direct delivery mode.
+    line: [bool; HARTS],
+    sink: S,
+}
+
+impl<S: VirtIrqSink, const HARTS: usize> VirtAplic<S, HARTS> {
+    /// Creates an APLIC domain mapped at guest physical address `base`, with interrupt sources
+    /// `1` to `num_sources` (the `riscv,num-sources` property of the device tree node).
+    pub fn new(base: GuestPhysAddr, num_sources: usize, sink: S) -> AxResult<Self> {
+        if num_sources >= MAX_IRQ_COUNT {
+            return ax_err!(InvalidInput, "too many APLIC interrupt sources");
+        }
+        Ok(Self {
+            base,
+            num_sources,
+            domaincfg: 0,
+            sourcecfg: [0; MAX_IRQ_COUNT],
+            target: [0; MAX_IRQ_COUNT],
+            pending: [0; SOURCE_WORDS],
+            enabled: [0; SOURCE_WORDS],
+            input: [0; SOURCE_WORDS],
+            idelivery: [0; HARTS],
+            iforce: [0; HARTS],
+            ithreshold: [0; HARTS],
+            line: [false; HARTS],
+            sink,
+        })
+    }
+
+    /// Returns the guest physical address the APLIC is mapped at.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns the size of the MMIO region of the APLIC, including the IDC structures.
+    pub const fn mmio_size() -> usize {
+        APLIC_MMIO_SIZE + HARTS * APLIC_IDC_SIZE
+    }
+
+    /// Returns whether `addr` is in the MMIO region of the APLIC.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base.as_usize()..self.base.as_usize() + Self::mmio_size()).contains(&addr.as_usize())
+    }
+
+    /// Sets the level of the input wire of interrupt source `source`.
+    ///
+    /// Whether this makes the interrupt pending depends on the source mode programmed by the
+    /// guest in `sourcecfg`.
+    pub fn set_source_level(&mut self, source: usize, level: bool) -> AxResult {
+        if source == 0 || source > self.num_sources {
+            return ax_err!(InvalidInput, "invalid APLIC interrupt source");
+        }
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        let old = self.input[word] & bit != 0;
+        if level {
+            self.input[word] |= bit;
+        } else {
+            self.input[word] &= !bit;
+        }
+        match self.source_mode(source) {
+            SM_EDGE_RISE if !old && level => self.set_pending(source, true),
+            SM_EDGE_FALL if old && !level => self.set_pending(source, true),
+            SM_LEVEL_HIGH | SM_LEVEL_LOW => {
+                let asserted = self.rectified_input(source);
+                if self.msi_mode() {
+                    // Cleared when the MSI is sent, or by the guest.
+                    if asserted {
+                        self.set_pending(source, true);
+                    }
+                } else {
+                    self.set_pending(source, asserted);
+                }
+            }
+            _ => {}
+        }
+        self.update();
+        Ok(())
+    }
+
+    /// Handles the guest MMIO access `access`, and returns the value read (0 for writes).
+    pub fn handle_mmio(&mut self, access: &MmioAccess) -> AxResult<usize> {
+        if !self.contains(access.addr) {
+            return ax_err!(InvalidInput, "address out of the APLIC MMIO region");
+        }
+        // All registers are 32-bit wide.
+        if access.width != 4 || access.addr.as_usize() % 4 != 0 {
+            return ax_err!(InvalidInput, "unsupported APLIC access width");
+        }
+        let offset = access.addr.as_usize() - self.base.as_usize();
+        match access.write_data {
+            Some(val) => {
+                self.write(offset, val as u32);
+                Ok(0)
+            }
+            None => Ok(self.read(offset) as usize),
+        }
+    }
+
+    /// Reads the 32-bit register at `offset`. Reserved and unimplemented registers read as zero.
+    fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            SOURCECFG_BASE..SETIP_BASE => self
+                .source_reg(offset, SOURCECFG_BASE)
+                .map_or(0, |source| self.sourcecfg[source]),
+            SETIP_BASE..SETIPNUM => self.bitmap_reg(offset, SETIP_BASE, &self.pending),
+            IN_CLRIP_BASE..CLRIPNUM => {
+                let word = (offset - IN_CLRIP_BASE) / 4;
+                (0..32)
+                    .filter(|bit| self.rectified_input(word * 32 + bit))
+                    .fold(0, |acc, bit| acc | 1 << bit)
+            }
+            SETIE_BASE..SETIENUM => self.bitmap_reg(offset, SETIE_BASE, &self.enabled),
+            TARGET_BASE..IDC_BASE => self
+                .source_reg(offset, TARGET_BASE)
+                .map_or(0, |source| self.target[source]),
+            IDC_BASE.. => match self.idc_reg(offset) {
+                Some((hart, IDC_IDELIVERY)) => self.idelivery[hart],
+                Some((hart, IDC_IFORCE)) => self.iforce[hart],
+                Some((hart, IDC_ITHRESHOLD)) => self.ithreshold[hart],
+                Some((hart, IDC_TOPI)) => self.topi(hart),
+                Some((hart, IDC_CLAIMI)) => self.claimi(hart),
+                _ => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    /// Writes the 32-bit register at `offset`. Writes to read-only, reserved and unimplemented
+    /// registers are ignored.
+    fn write(&mut self, offset: usize, val: u32) {
+        match offset {
+            DOMAINCFG => self.domaincfg = val & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG_BASE..SETIP_BASE => {
+                if let Some(source) = self.source_reg(offset, SOURCECFG_BASE) {
+                    self.set_sourcecfg(source, val);
+                }
+            }
+            SETIP_BASE..SETIPNUM => self.for_each_bit(offset, SETIP_BASE, val, Self::set_ip),
+            SETIPNUM | SETIPNUM_LE => self.set_ip(val as usize),
+            IN_CLRIP_BASE..CLRIPNUM => self.for_each_bit(offset, IN_CLRIP_BASE, val, Self::clr_ip),
+            CLRIPNUM => self.clr_ip(val as usize),
+            SETIE_BASE..SETIENUM => {
+                self.for_each_bit(offset, SETIE_BASE, val, |s, source| s.set_ie(source, true))
+            }
+            SETIENUM => self.set_ie(val as usize, true),
+            CLRIE_BASE..CLRIENUM => {
+                self.for_each_bit(offset, CLRIE_BASE, val, |s, source| s.set_ie(source, false))
+            }
+            CLRIENUM => self.set_ie(val as usize, false),
+            TARGET_BASE..IDC_BASE => {
+                if let Some(source) = self.source_reg(offset, TARGET_BASE) {
+                    self.set_target(source, val);
+                }
+            }
+            IDC_BASE.. => match self.idc_reg(offset) {
+                Some((hart, IDC_IDELIVERY)) => self.idelivery[hart] = val & 1,
+                Some((hart, IDC_IFORCE)) => self.iforce[hart] = val & 1,
+                Some((hart, IDC_ITHRESHOLD)) => self.ithreshold[hart] = val & TARGET_IPRIO_MASK,
+                _ => {}
+            },
+            _ => {}
+        }
+        self.update();
+    }
+
+    fn set_sourcecfg(&mut self, source: usize, val: u32) {
+        // Without child domains, the delegate bit is read-only zero.
+        let mode = match val & SOURCECFG_SM_MASK {
+            mode @ (SM_DETACHED | SM_EDGE_RISE | SM_EDGE_FALL | SM_LEVEL_HIGH | SM_LEVEL_LOW) => {
+                mode
+            }
+            _ => SM_INACTIVE,
+        };
+        self.sourcecfg[source] = mode;
+        if mode == SM_INACTIVE {
+            // Inactive sources have their pending and enable bits and target read-only zero.
+            self.set_pending(source, false);
+            self.enabled[source / 32] &= !(1 << (source % 32));
+            self.target[source] = 0;
+        } else if matches!(mode, SM_LEVEL_HIGH | SM_LEVEL_LOW) && !self.msi_mode() {
+            let asserted = self.rectified_input(source);
+            self.set_pending(source, asserted);
+        }
+    }
+
+    fn set_target(&mut self, source: usize, val: u32) {
+        if self.source_mode(source) == SM_INACTIVE {
+            return;
+        }
+        let hart = val & !((1 << TARGET_HART_SHIFT) - 1);
+        self.target[source] = if self.msi_mode() {
+            hart | val & (TARGET_GUEST_MASK << TARGET_GUEST_SHIFT | TARGET_EIID_MASK)
+        } else {
+            // A priority of 0 is not allowed and reads as 1.
+            hart | (val & TARGET_IPRIO_MASK).max(1)
+        };
+    }
+
+    /// Sets the pending bit of `source` by a guest write, if the source mode allows it.
+    fn set_ip(&mut self, source: usize) {
+        match self.source_mode(source) {
+            SM_DETACHED | SM_EDGE_RISE | SM_EDGE_FALL => self.set_pending(source, true),
+            SM_LEVEL_HIGH | SM_LEVEL_LOW if self.msi_mode() && self.rectified_input(source) => {
+                self.set_pending(source, true)
+            }
+            _ => {}
+        }
+    }
+
+    /// Clears the pending bit of `source` by a guest write, if the source mode allows it.
+    fn clr_ip(&mut self, source: usize) {
+        match self.source_mode(source) {
+            SM_INACTIVE => {}
+            SM_LEVEL_HIGH | SM_LEVEL_LOW if !self.msi_mode() => {}
+            _ => self.set_pending(source, false),
+        }
+    }
+
+    fn set_ie(&mut self, source: usize, enable: bool) {
+        if self.source_mode(source) == SM_INACTIVE {
+            return;
+        }
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        if enable {
+            self.enabled[word] |= bit;
+        } else {
+            self.enabled[word] &= !bit;
+        }
+    }
+
+    /// Returns the highest priority interrupt pending for `hart` in direct delivery mode, in the
+    /// format of `topi`: the source in bits 25:16 and its priority in bits 7:0.
+    fn topi(&self, hart: usize) -> u32 {
+        let mut best: Option<(usize, u32)> = None;
+        for word in 0..SOURCE_WORDS {
+            let mut candidates = self.pending[word] & self.enabled[word];
+            while candidates != 0 {
+                let source = word * 32 + candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                let target = self.target[source];
+                if (target >> TARGET_HART_SHIFT) as usize != hart {
+                    continue;
+                }
+                // Lower values are higher priorities.
+                let prio = target & TARGET_IPRIO_MASK;
+                let threshold = self.ithreshold[hart];
+                if (threshold == 0 || prio < threshold) && best.is_none_or(|(_, p)| prio < p) {
+                    best = Some((source, prio));
+                }
+            }
+        }
+        best.map_or(0, |(source, prio)| (source as u32) << 16 | prio)
+    }
+
+    /// Claims the highest priority interrupt pending for `hart`, returning it in the format of
+    /// `topi`.
+    fn claimi(&mut self, hart: usize) -> u32 {
+        let topi = self.topi(hart);
+        let source = (topi >> 16) as usize;
+        if source == 0 {
+            // Spurious or forced interrupt.
+            self.iforce[hart] = 0;
+        } else if !matches!(self.source_mode(source), SM_LEVEL_HIGH | SM_LEVEL_LOW) {
+            // Level-sensitive sources stay pending as long as their input is asserted.
+            self.set_pending(source, false);
+        }
+        self.update();
+        topi
+    }
+
+    /// Delivers pending interrupts: forwards MSIs in MSI delivery mode, or updates the external
+    /// interrupt line of each hart in direct delivery mode.
+    fn update(&mut self) {
+        let enabled = self.domaincfg & DOMAINCFG_IE != 0;
+        if self.msi_mode() {
+            for hart in 0..HARTS {
+                self.set_line(hart, false);
+            }
+            if !enabled {
+                return;
+            }
+            for word in 0..SOURCE_WORDS {
+                let mut ready = self.pending[word] & self.enabled[word];
+                while ready != 0 {
+                    let source = word * 32 + ready.trailing_zeros() as usize;
+                    ready &= ready - 1;
+                    let target = self.target[source];
+                    let sent = self.sink.send_msi(
+                        (target >> TARGET_HART_SHIFT) as usize,
+                        ((target >> TARGET_GUEST_SHIFT) & TARGET_GUEST_MASK) as usize,
+                        (target & TARGET_EIID_MASK) as usize,
+                    );
+                    // Sent again on the next update if it failed.
+                    match sent {
+                        Ok(()) => self.pending[word] &= !(1 << (source % 32)),
+                        Err(err) => warn!("Failed to send MSI of source {source}: {err:?}"),
+                    }
+                }
+            }
+        } else {
+            for hart in 0..HARTS {
+                let level = enabled
+                    && self.idelivery[hart] != 0
+                    && (self.iforce[hart] != 0 || self.topi(hart) != 0);
+                self.set_line(hart, level);
+            }
+        }
+    }
+
+    fn set_line(&mut self, hart: usize, level: bool) {
+        if self.line[hart] != level {
+            self.line[hart] = level;
+            self.sink
+                .set_irq_level(hart, VirtInterrupt::SupervisorExternal, level);
+        }
+    }
+
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    fn source_mode(&self, source: usize) -> u32 {
+        if source == 0 || source > self.num_sources {
+            return SM_INACTIVE;
+        }
+        self.sourcecfg[source]
+    }
+
+    /// Returns the input of `source` after inversion for the falling-edge and low-level modes.
+    fn rectified_input(&self, source: usize) -> bool {
+        let mode = self.source_mode(source);
+        if matches!(mode, SM_INACTIVE | SM_DETACHED) {
+            return false;
+        }
+        let level = self.input[source / 32] & (1 << (source % 32)) != 0;
+        level == matches!(mode, SM_EDGE_RISE | SM_LEVEL_HIGH)
+    }
+
+    fn set_pending(&mut self, source: usize, pending: bool) {
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        if pending {
+            self.pending[word] |= bit;
+        } else {
+            self.pending[word] &= !bit;
+        }
+    }
+
+    /// Returns the source of the per-source register at `offset` in the array at `base`, which
+    /// starts at source 1.
+    fn source_reg(&self, offset: usize, base: usize) -> Option<usize> {
+        let source = (offset - base) / 4 + 1;
+        (source <= self.num_sources).then_some(source)
+    }
+
+    /// Reads the word of `bitmap` selected by the register at `offset` in the array at `base`.
+    fn bitmap_reg(&self, offset: usize, base: usize, bitmap: &[u32; SOURCE_WORDS]) -> u32 {
+        bitmap.get((offset - base) / 4).copied().unwrap_or(0)
+    }
+
+    /// Calls `f` for each source whose bit is set in `val`, written to the bitmap register at
+    /// `offset` in the array at `base`.
+    fn for_each_bit(
+        &mut self,
+        offset: usize,
+        base: usize,
+        val: u32,
+        mut f: impl FnMut(&mut Self, usize),
+    ) {
+        let word = (offset - base) / 4;
+        let mut bits = val;
+        while bits != 0 {
+            f(self, word * 32 + bits.trailing_zeros() as usize);
+            bits &= bits - 1;
+        }
+    }
+
+    /// Returns the hart and register offset within the IDC structure of the register at
+    /// `offset`.
+    fn idc_reg(&self, offset: usize) -> Option<(usize, usize)> {
+        let hart = (offset - IDC_BASE) / APLIC_IDC_SIZE;
+        let reg = (offset - IDC_BASE) % APLIC_IDC_SIZE;
+        (hart < HARTS).then_some((hart, reg))
+    }
+}
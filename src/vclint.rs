@@ -0,0 +1,209 @@
+//! Emulated Core-Local Interruptor (CLINT) and ACLINT devices for guests.
+//!
+//! Guests ported from M-mode may program the timer and send IPIs through the CLINT MMIO instead
+//! of the SBI. As the guest runs in VS-mode, machine-level interrupts are delivered as their
+//! supervisor-level counterparts: `msip` drives the virtual supervisor software interrupt, and
+//! `mtimecmp` programs the virtual timer of the vCPU. Hart index `n` is the vCPU with ID `n`.
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use riscv::register::time;
+
+use crate::mmio::MmioAccess;
+use crate::virq::{VirtInterrupt, VirtIrqSink};
+
+/// Size of the CLINT MMIO region, made of an ACLINT MSWI and MTIMER device.
+pub const CLINT_MMIO_SIZE: usize = 0x1_0000;
+/// Size of the MMIO region of an ACLINT SSWI device.
+pub const ACLINT_SSWI_MMIO_SIZE: usize = 0x4000;
+
+const MSIP_BASE: usize = 0x0;
+const MTIMECMP_BASE: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+const MTIME_HI: usize = 0xbffc;
+
+/// An emulated CLINT with `HARTS` harts, one per vCPU.
+///
+/// Driven by guest MMIO accesses with [`Self::handle_mmio`], on the hart the accessing vCPU is
+/// bound to. Software interrupts are set through the [`VirtIrqSink`], and `mtimecmp` writes
+/// program the virtual timer with [`VirtIrqSink::set_timer`]. `mtime` is the `time` CSR as the
+/// guest sees it, offset by the `htimedelta` of the vCPU, and is read-only.
+pub struct VirtClint<S: VirtIrqSink, const HARTS: usize> {
+    base: GuestPhysAddr,
+    msip: [bool; HARTS],
+    mtimecmp: [u64; HARTS],
+    sink: S,
+}
+
+impl<S: VirtIrqSink, const HARTS: usize> VirtClint<S, HARTS> {
+    /// Creates a CLINT mapped at guest physical address `base`.
+    pub fn new(base: GuestPhysAddr, sink: S) -> Self {
+        Self {
+            base,
+            msip: [false; HARTS],
+            mtimecmp: [u64::MAX; HARTS],
+            sink,
+        }
+    }
+
+    /// Returns the guest physical address the CLINT is mapped at.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns whether `addr` is in the MMIO region of the CLINT.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base.as_usize()..self.base.as_usize() + CLINT_MMIO_SIZE).contains(&addr.as_usize())
+    }
+
+    /// Handles the guest MMIO access `access`, and returns the value read (0 for writes).
+    ///
+    /// Fails if the access programs the timer and [`VirtIrqSink::set_timer`] fails.
+    pub fn handle_mmio(&mut self, access: &MmioAccess) -> AxResult<usize> {
+        if !self.contains(access.addr) {
+            return ax_err!(InvalidInput, "address out of the CLINT MMIO region");
+        }
+        let offset = access.addr.as_usize() - self.base.as_usize();
+        // `msip` registers are 32-bit wide, 64-bit registers can also be accessed by halves.
+        let valid = match offset {
+            MSIP_BASE..MTIMECMP_BASE => access.width == 4,
+            _ => access.width == 4 || access.width == 8,
+        };
+        if !valid || offset % access.width != 0 {
+            return ax_err!(InvalidInput, "unsupported CLINT access width");
+        }
+        match access.write_data {
+            Some(val) => {
+                self.write(offset, access.width, val as u64)?;
+                Ok(0)
+            }
+            None => Ok(self.read(offset, access.width) as usize),
+        }
+    }
+
+    /// Reads the register at `offset`. Reserved registers read as zero.
+    fn read(&self, offset: usize, width: usize) -> u64 {
+        match offset {
+            MSIP_BASE..MTIMECMP_BASE => match self.msip.get((offset - MSIP_BASE) / 4) {
+                Some(&msip) => msip as u64,
+                None => 0,
+            },
+            MTIMECMP_BASE..MTIME => match self.mtimecmp.get((offset - MTIMECMP_BASE) / 8) {
+                Some(&mtimecmp) => read_half(mtimecmp, offset, width),
+                None => 0,
+            },
+            MTIME | MTIME_HI => read_half(time::read64().wrapping_add(time_delta()), offset, width),
+            _ => 0,
+        }
+    }
+
+    /// Writes the register at `offset`. Writes to `mtime` and reserved registers are ignored.
+    fn write(&mut self, offset: usize, width: usize, val: u64) -> AxResult {
+        match offset {
+            MSIP_BASE..MTIMECMP_BASE => {
+                let hart = (offset - MSIP_BASE) / 4;
+                if hart < HARTS {
+                    self.msip[hart] = val & 1 != 0;
+                    self.sink
+                        .set_irq_level(hart, VirtInterrupt::SupervisorSoft, self.msip[hart]);
+                }
+            }
+            MTIMECMP_BASE..MTIME => {
+                let hart = (offset - MTIMECMP_BASE) / 8;
+                if hart < HARTS {
+                    self.mtimecmp[hart] = write_half(self.mtimecmp[hart], offset, width, val);
+                    self.sink
+                        .set_timer(hart, host_deadline(self.mtimecmp[hart]))?;
+                }
+            }
+            MTIME | MTIME_HI => warn!("Ignored write to CLINT mtime"),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// An emulated ACLINT supervisor-level software interrupt (SSWI) device with `HARTS` harts, one
+/// per vCPU.
+///
+/// Writing 1 to `setssip` of a hart sets its virtual supervisor software interrupt, which is
+/// cleared by the guest through `sip`.
+pub struct VirtAclintSswi<S: VirtIrqSink, const HARTS: usize> {
+    base: GuestPhysAddr,
+    sink: S,
+}
+
+impl<S: VirtIrqSink, const HARTS: usize> VirtAclintSswi<S, HARTS> {
+    /// Creates an SSWI device mapped at guest physical address `base`.
+    pub fn new(base: GuestPhysAddr, sink: S) -> Self {
+        Self { base, sink }
+    }
+
+    /// Returns the guest physical address the SSWI device is mapped at.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns whether `addr` is in the MMIO region of the SSWI device.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base.as_usize()..self.base.as_usize() + ACLINT_SSWI_MMIO_SIZE)
+            .contains(&addr.as_usize())
+    }
+
+    /// Handles the guest MMIO access `access`, and returns the value read (0 for writes).
+    pub fn handle_mmio(&mut self, access: &MmioAccess) -> AxResult<usize> {
+        if !self.contains(access.addr) {
+            return ax_err!(InvalidInput, "address out of the SSWI MMIO region");
+        }
+        // All registers are 32-bit wide.
+        if access.width != 4 || access.addr.as_usize() % 4 != 0 {
+            return ax_err!(InvalidInput, "unsupported SSWI access width");
+        }
+        // `setssip` registers always read as zero.
+        let hart = (access.addr.as_usize() - self.base.as_usize()) / 4;
+        if let Some(val) = access.write_data {
+            if hart < HARTS && val & 1 != 0 {
+                self.sink
+                    .set_irq_level(hart, VirtInterrupt::SupervisorSoft, true);
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Returns `htimedelta` of the current hart, the offset of the guest's `time` from the host's.
+fn time_delta() -> u64 {
+    let delta: u64;
+    unsafe {
+        core::arch::asm!("csrr {}, 0x605", out(reg) delta); // 0x605 => htimedelta
+    }
+    delta
+}
+
+/// Converts `deadline` from ticks of the `time` CSR as the guest running on the current hart sees
+/// it to ticks of the host's.
+pub(crate) fn host_deadline(deadline: u64) -> u64 {
+    let now = time::read64();
+    let guest_now = now.wrapping_add(time_delta());
+    now.saturating_add(deadline.saturating_sub(guest_now))
+}
+
+/// Reads the half of 64-bit register `reg` at `offset`, or the whole register for 64-bit
+/// accesses.
+fn read_half(reg: u64, offset: usize, width: usize) -> u64 {
+    match (width, offset % 8) {
+        (4, 0) => reg & 0xffff_ffff,
+        (4, _) => reg >> 32,
+        _ => reg,
+    }
+}
+
+/// Returns 64-bit register `reg` with the half at `offset` replaced by `val`, or `val` for 64-bit
+/// accesses.
+fn write_half(reg: u64, offset: usize, width: usize, val: u64) -> u64 {
+    match (width, offset % 8) {
+        (4, 0) => reg & !0xffff_ffff | val & 0xffff_ffff,
+        (4, _) => reg & 0xffff_ffff | val << 32,
+        _ => val,
+    }
+}
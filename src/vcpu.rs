@@ -1,14 +1,36 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use riscv::register::hstatus;
-use riscv::register::{htinst, htval, hvip, scause, sie, sstatus, stval};
+use riscv::register::{htinst, htval, scause, sie, sstatus, stval};
 use rustsbi::{Forward, RustSBI};
+use sbi_spec::binary::HartMask;
 use sbi_spec::{hsm, legacy};
 
-use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
-use axerrno::AxResult;
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxError, AxResult, ax_err};
 use axvcpu::{AxVCpuExitReason, AxVCpuHal};
 
+use crate::aia::{self, GuestInterruptFile};
+use crate::consts::traps;
+use crate::consts::{SSTATUS_SIE, SSTATUS_SPIE, SSTATUS_SPP, STVEC_MODE_MASK};
+use crate::dirty;
+use crate::fault::{GuestPageFault, GuestPageFaultKind};
+use crate::guest_mem::{self, GuestMemFault, GuestMemResult};
+use crate::insn::{MemInsn, MemOp, sign_extend, truncate};
+use crate::isa::HostIsaFeatures;
+use crate::mmio::MmioAccess;
+use crate::page_walk::{self, GStageMode, GuestTranslation, HGATP_VMID_MASK, HGATP_VMID_SHIFT};
+use crate::percpu::{HartState, TrapDelegation};
 use crate::regs::*;
-use crate::{EID_HVC, RISCVVCpuCreateConfig};
+use crate::tlb;
+use crate::vclint;
+use crate::virq::{PendingVirtInterrupts, VirtInterrupt};
+use crate::vmid::VmVmid;
+use crate::{
+    EID_HVC, GUEST_EXTERNAL_EXIT_VECTOR, KICK_EXIT_VECTOR, RISCVVCpuCreateConfig, RISCVVCpuHostIf,
+};
+
+const PAGE_SIZE: usize = 0x1000;
 
 unsafe extern "C" {
     fn _run_guest(state: *mut VmCpuRegisters);
@@ -23,6 +45,25 @@ pub struct VCpuConfig {}
 pub struct RISCVVCpu<H: AxVCpuHal> {
     regs: VmCpuRegisters,
     sbi: RISCVVCpuSbi,
+    emulate_misaligned: bool,
+    gstage_mode: GStageMode,
+    trap_delegation: Option<TrapDelegation>,
+    // The delegation of the CPU, replaced by the one of the vCPU while it is bound.
+    host_delegation: Option<TrapDelegation>,
+    vmid: VmVmid,
+    virq: PendingVirtInterrupts,
+    imsic: GuestInterruptFile,
+    // Whether the host may receive its external interrupts through an IMSIC, on the hart the vCPU
+    // is bound to.
+    host_aia: bool,
+    // Whether the hart the vCPU is bound to supports Svinval, to batch TLB invalidations.
+    svinval: bool,
+    // Set by `kick`, consumed when the vCPU exits or is about to enter the guest.
+    kick_requested: AtomicBool,
+    // The hart the vCPU is bound to, or `usize::MAX` if it is not bound.
+    bound_hart: AtomicUsize,
+    // Set by `request_gstage_flush`, consumed when the vCPU is about to enter the guest.
+    gstage_flush_requested: AtomicBool,
     _marker: core::marker::PhantomData<H>,
 }
 
@@ -57,6 +98,18 @@ impl<H: AxVCpuHal> axvcpu::AxArchVCpu for RISCVVCpu<H> {
         Ok(Self {
             regs: VmCpuRegisters::default(),
             sbi: RISCVVCpuSbi::default(),
+            emulate_misaligned: config.emulate_misaligned,
+            gstage_mode: config.gstage_mode,
+            trap_delegation: config.trap_delegation,
+            host_delegation: None,
+            vmid: VmVmid::default(),
+            virq: PendingVirtInterrupts::default(),
+            imsic: GuestInterruptFile::default(),
+            host_aia: false,
+            svinval: false,
+            kick_requested: AtomicBool::new(false),
+            bound_hart: AtomicUsize::new(usize::MAX),
+            gstage_flush_requested: AtomicBool::new(false),
             _marker: core::marker::PhantomData,
         })
     }
@@ -85,7 +138,20 @@ impl<H: AxVCpuHal> axvcpu::AxArchVCpu for RISCVVCpu<H> {
     }
 
     fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
-        self.regs.virtual_hs_csrs.hgatp = 8usize << 60 | usize::from(ept_root) >> 12;
+        // The root page table of the G-stage is 16 KiB.
+        if ept_root.as_usize() % 0x4000 != 0 {
+            return ax_err!(
+                InvalidInput,
+                "G-stage root page table is not 16 KiB aligned"
+            );
+        }
+        // Checked again on `bind`, on the hart the vCPU runs on.
+        if !HostIsaFeatures::current().supports_gstage_mode(self.gstage_mode) {
+            return ax_err!(Unsupported, "G-stage translation mode is not supported");
+        }
+        self.regs.virtual_hs_csrs.hgatp = self.gstage_mode.hgatp(ept_root);
+        // The vCPUs sharing the page table belong to the same VM, and share its VMIDs.
+        self.vmid.register(ept_root.as_usize());
         Ok(())
     }
 
@@ -96,11 +162,30 @@ impl<H: AxVCpuHal> axvcpu::AxArchVCpu for RISCVVCpu<H> {
             sie::set_ssoft();
             sie::set_stimer();
         }
+        // With interrupts disabled, a kick after this check leaves its IPI pending, which makes
+        // the guest exit right after entry.
+        if self.kick_requested.swap(false, Ordering::AcqRel) {
+            unsafe {
+                sie::clear_sext();
+                sie::clear_ssoft();
+                sie::clear_stimer();
+                sstatus::set_sie();
+            }
+            return Ok(AxVCpuExitReason::ExternalInterrupt {
+                vector: KICK_EXIT_VECTOR as _,
+            });
+        }
+        if self.gstage_flush_requested.swap(false, Ordering::AcqRel) {
+            tlb::flush_gstage(self.vmid(), None, self.svinval);
+        }
+        self.imsic.deliver_queued();
+        let entry_hvip = self.virq.load_to_hvip();
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
             _run_guest(&mut self.regs);
         }
+        self.virq.sync_from_hvip(entry_hvip);
         unsafe {
             sie::clear_sext();
             sie::clear_ssoft();
@@ -111,17 +196,51 @@ impl<H: AxVCpuHal> axvcpu::AxArchVCpu for RISCVVCpu<H> {
     }
 
     fn bind(&mut self) -> AxResult {
+        let hart = crate_interface::call_interface!(RISCVVCpuHostIf::current_hart_id);
+        let Some(state) = HartState::of(hart) else {
+            return ax_err!(BadState, "virtualization is not enabled on this CPU");
+        };
+        let features = state.features();
+        if !features.supports_gstage_mode(self.gstage_mode) {
+            return ax_err!(Unsupported, "G-stage translation mode is not supported");
+        }
+        self.host_aia = features.ssaia;
+        self.svinval = features.svinval;
+        // TLB entries tagged with the VMID of the VM belong to it, unless the VMID is shared.
+        let (vmid, flush) = self.vmid.vmid(state);
+        let hgatp = &mut self.regs.virtual_hs_csrs.hgatp;
+        *hgatp = *hgatp & !(HGATP_VMID_MASK << HGATP_VMID_SHIFT) | vmid << HGATP_VMID_SHIFT;
         unsafe {
             core::arch::asm!(
                 "csrw hgatp, {hgatp}",
-                hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
+                "csrw 0x605, {htimedelta}", // 0x605 => htimedelta
+                hgatp = in(reg) *hgatp,
+                htimedelta = in(reg) self.regs.vs_csrs.htimedelta,
             );
-            core::arch::riscv64::hfence_gvma_all();
+            if flush {
+                core::arch::riscv64::hfence_gvma_all();
+            }
+        }
+        self.regs.guest_regs.hstatus = self.imsic.guest_hstatus(self.regs.guest_regs.hstatus);
+        self.imsic.bind(state, hart, self.regs.vs_csrs.vsiselect);
+        if let Some(delegation) = &self.trap_delegation {
+            self.host_delegation = Some(TrapDelegation::read());
+            unsafe {
+                delegation.write();
+            }
         }
+        self.bound_hart.store(hart, Ordering::Release);
         Ok(())
     }
 
     fn unbind(&mut self) -> AxResult {
+        self.regs.vs_csrs.vsiselect = self.imsic.unbind();
+        if let Some(delegation) = self.host_delegation.take() {
+            unsafe {
+                delegation.write();
+            }
+        }
+        self.bound_hart.store(usize::MAX, Ordering::Release);
         Ok(())
     }
 
@@ -158,6 +277,314 @@ impl<H: AxVCpuHal> RISCVVCpu<H> {
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Asks the vCPU to exit promptly, from any physical CPU.
+    ///
+    /// If the vCPU is running, it exits with an [`AxVCpuExitReason::ExternalInterrupt`] of
+    /// vector [`KICK_EXIT_VECTOR`]. Otherwise, the next [`run`](axvcpu::AxArchVCpu::run) returns
+    /// that exit without entering the guest.
+    ///
+    /// The kick is an IPI to the hart the vCPU runs on, acknowledged on exit. Host IPIs sent to
+    /// the hart at the same time are acknowledged with it, so the VMM must run its IPI handler on
+    /// a kick exit too.
+    pub fn kick(&self) {
+        self.kick_requested.store(true, Ordering::SeqCst);
+        self.interrupt_bound_hart();
+    }
+
+    /// Requests a flush of the G-stage TLB entries of the guest on the hart the vCPU runs on,
+    /// before it enters the guest again. Can be called from any physical CPU.
+    ///
+    /// Used after the G-stage page table is changed while the vCPU may be running. A running vCPU
+    /// is interrupted, and exits with an [`AxVCpuExitReason::ExternalInterrupt`] of a host IPI.
+    /// See [`Self::flush_guest_phys`] to invalidate the entries of the whole VM.
+    pub fn request_gstage_flush(&self) {
+        self.gstage_flush_requested.store(true, Ordering::SeqCst);
+        self.interrupt_bound_hart();
+    }
+
+    /// Injects the virtual interrupt `irq` into the guest.
+    ///
+    /// The interrupt stays pending until it is cleared with [`Self::clear_interrupt`], or by the
+    /// guest itself for software interrupts. It is delivered the next time the vCPU enters the
+    /// guest. This can be called from any CPU.
+    pub fn inject_interrupt(&self, irq: VirtInterrupt) {
+        self.virq.inject(irq);
+    }
+
+    /// Sends an MSI with identity `eiid` to the guest interrupt file of the vCPU, as a device of
+    /// the guest would by writing the guest's IMSIC.
+    ///
+    /// The interrupt is set pending in the file the next time the vCPU enters the guest. A
+    /// running vCPU is interrupted, and exits with an [`AxVCpuExitReason::ExternalInterrupt`] of a
+    /// host IPI; a vCPU waiting for interrupts must be woken by the VMM. This can be called from
+    /// any CPU. Fails if no guest interrupt file is assigned to the vCPU.
+    pub fn send_msi(&self, eiid: usize) -> AxResult {
+        self.imsic.queue_msi(eiid)?;
+        self.interrupt_bound_hart();
+        Ok(())
+    }
+
+    /// Clears the virtual interrupt `irq` of the guest. This can be called from any CPU.
+    pub fn clear_interrupt(&self, irq: VirtInterrupt) {
+        self.virq.clear(irq);
+    }
+
+    /// Programs the virtual timer of the guest to fire at `deadline`, in ticks of the `time` CSR
+    /// of the host, and clears the pending virtual timer interrupt. The guest sees `time` offset
+    /// by the `htimedelta` of its registers, loaded on [`bind`](axvcpu::AxArchVCpu::bind).
+    ///
+    /// The timer is backed by the timer of the current physical CPU, so this must be called on
+    /// the CPU running the vCPU.
+    pub fn set_timer(&self, deadline: u64) {
+        sbi_rt::set_timer(deadline);
+        self.clear_interrupt(VirtInterrupt::SupervisorTimer);
+    }
+
+    /// Returns whether the virtual interrupt `irq` is pending for the guest.
+    pub fn is_interrupt_pending(&self, irq: VirtInterrupt) -> bool {
+        self.virq.bits() & irq.hvip_bit() != 0
+    }
+
+    /// Invalidates the G-stage TLB entries of the guest physical addresses `start..start + size`
+    /// of the VM on all harts it ran on, e.g. after unmapping them from the address space of the
+    /// guest.
+    ///
+    /// All vCPUs of the VM share its VMIDs, so calling this on any of them is enough. Can be
+    /// called from any physical CPU, after [`set_ept_root`](axvcpu::AxArchVCpu::set_ept_root).
+    pub fn flush_guest_phys(&self, start: GuestPhysAddr, size: usize) {
+        self.vmid.flush(Some((start.as_usize(), size)));
+    }
+
+    /// Invalidates all G-stage TLB entries of the VM on all harts.
+    ///
+    /// See [`Self::flush_guest_phys`].
+    pub fn flush_guest_phys_all(&self) {
+        self.vmid.flush(None);
+    }
+
+    /// Invalidates the VS-stage TLB entries of the guest virtual addresses `start..start + size`
+    /// in address space `asid`, or in all address spaces, on the current hart. Used to emulate
+    /// remote fences requested by the guest.
+    ///
+    /// The vCPU must be bound to the current hart.
+    pub fn flush_guest_virt(&self, start: GuestVirtAddr, size: usize, asid: Option<usize>) {
+        tlb::flush_vsstage(asid, Some((start.as_usize(), size)), self.svinval);
+    }
+
+    /// Invalidates all VS-stage TLB entries of the guest in address space `asid`, or in all
+    /// address spaces, on the current hart.
+    ///
+    /// The vCPU must be bound to the current hart.
+    pub fn flush_guest_virt_all(&self, asid: Option<usize>) {
+        tlb::flush_vsstage(asid, None, self.svinval);
+    }
+
+    /// Sends a host IPI to the hart the vCPU is bound to, if any.
+    fn interrupt_bound_hart(&self) {
+        let hart = self.bound_hart.load(Ordering::SeqCst);
+        if hart != usize::MAX {
+            let ret = sbi_rt::send_ipi(HartMask::from_mask_base(1, hart));
+            if ret.is_err() {
+                warn!("Failed to interrupt vCPU on hart {hart}: {ret:?}");
+            }
+        }
+    }
+
+    /// Handles a guest write fault on `gpa` caused by dirty logging. Returns whether the guest can
+    /// be resumed.
+    fn handle_dirty_fault(&self, gpa: GuestPhysAddr) -> bool {
+        if !dirty::handle_write_fault::<H::MmHal>(self.regs.virtual_hs_csrs.hgatp, gpa) {
+            return false;
+        }
+        tlb::flush_gstage(self.vmid(), Some((gpa.as_usize(), 1)), self.svinval);
+        true
+    }
+
+    /// Returns the VMID the vCPU uses on the hart it is bound to.
+    fn vmid(&self) -> usize {
+        self.regs.virtual_hs_csrs.hgatp >> HGATP_VMID_SHIFT & HGATP_VMID_MASK
+    }
+
+    /// Sets the trap delegation of the vCPU, or `None` to use the one of the CPU. Takes effect on
+    /// the next [`bind`](axvcpu::AxArchVCpu::bind).
+    ///
+    /// For example, breakpoints must not be delegated to a guest being debugged.
+    pub fn set_trap_delegation(&mut self, delegation: Option<TrapDelegation>) {
+        self.trap_delegation = delegation;
+    }
+
+    /// Assigns guest interrupt file `file` of the current hart's IMSIC to the vCPU, or none.
+    ///
+    /// The file delivers MSIs directly to the guest while the vCPU runs. When the vCPU is moved
+    /// to another file, the state of the previous file is moved to the new one on the next
+    /// [`bind`](axvcpu::AxArchVCpu::bind), and the previous file is reset; a file first used by
+    /// the vCPU is reset too. While the vCPU is unbound, an interrupt received by the file makes
+    /// the vCPU running on the hart exit, see [`GUEST_EXTERNAL_EXIT_VECTOR`].
+    /// Fails if the hart does not support AIA or does not implement the file.
+    pub fn set_guest_interrupt_file(&mut self, file: Option<usize>) -> AxResult {
+        self.imsic.assign(file)?;
+        self.regs.guest_regs.hstatus = self.imsic.guest_hstatus(self.regs.guest_regs.hstatus);
+        Ok(())
+    }
+
+    /// Gets the trap CSRs recorded on the last VM exit.
+    ///
+    /// When `run` fails because of a trap that could not be handled, this describes the trap.
+    pub fn trap_state(&self) -> &VmCpuTrapState {
+        &self.regs.trap_csrs
+    }
+
+    /// Decodes the guest-page fault that caused the last VM exit, or returns `None` if the last VM
+    /// exit was not caused by a guest-page fault.
+    ///
+    /// In particular, this tells faults on implicit accesses to the guest's page table apart
+    /// from the ones on accesses by guest instructions, and gives the guest virtual address of the
+    /// fault, which [`AxVCpuExitReason::NestedPageFault`] does not carry.
+    pub fn guest_page_fault(&self) -> Option<GuestPageFault> {
+        GuestPageFault::from_trap_state(&self.regs.trap_csrs)
+    }
+
+    /// Decodes the guest MMIO access that caused the last VM exit.
+    ///
+    /// To be called when the last VM exit is a [`AxVCpuExitReason::NestedPageFault`] on a guest
+    /// physical address the VMM emulates. The access must then be completed with
+    /// [`Self::complete_mmio_access`]. Faults on implicit accesses to the guest's page table (see
+    /// [`GuestPageFaultKind::PageTableWalk`]) are never decoded as MMIO accesses.
+    pub fn decode_mmio_access(&self) -> AxResult<MmioAccess> {
+        let Some(fault) = self.guest_page_fault() else {
+            return ax_err!(BadState, "last VM exit is not a guest-page fault");
+        };
+        if fault.kind != GuestPageFaultKind::Access
+            || fault.access_flags.contains(MappingFlags::EXECUTE)
+        {
+            return ax_err!(
+                InvalidInput,
+                "guest-page fault is not caused by a load or store"
+            );
+        }
+        match self.decode_trapped_mem_insn() {
+            Ok(Some(insn)) => Ok(MmioAccess::new(fault.gpa, insn, |reg| self.get_gpr(reg))),
+            Ok(None) => ax_err!(Unsupported, "unsupported guest MMIO instruction"),
+            Err(_) => ax_err!(BadAddress, "failed to fetch guest MMIO instruction"),
+        }
+    }
+
+    /// Completes the guest MMIO access `access`, by writing `read_val` to the destination register
+    /// for reads, and moving the guest past the faulting instruction.
+    ///
+    /// `read_val` is ignored for writes.
+    pub fn complete_mmio_access(&mut self, access: &MmioAccess, read_val: usize) {
+        let insn = access.insn();
+        if let MemOp::Load { rd, signed } = insn.op {
+            let mut val = truncate(read_val, insn.width);
+            if signed {
+                val = sign_extend(val, insn.width);
+            }
+            self.set_gpr_from_gpr_index(rd, val);
+        }
+        self.advance_pc(insn.len);
+    }
+
+    /// Reads guest memory at guest virtual address `gva` into `buf`.
+    ///
+    /// The address is translated by the guest's current page table, with the privilege the guest
+    /// had when it last exited (`sstatus.SPP`), so that guest user mode cannot reach pages of the
+    /// guest kernel through the VMM. Must be called on the physical CPU the vCPU is bound to.
+    pub fn read_guest_virt(&self, gva: GuestVirtAddr, buf: &mut [u8]) -> GuestMemResult {
+        guest_mem::read_guest_virt(gva, buf, self.trapped_in_supervisor())
+    }
+
+    /// Writes `buf` to guest memory at guest virtual address `gva`. The pages write-protected by
+    /// a [`DirtyLog`](crate::DirtyLog) are logged as written, as for the writes of the guest.
+    ///
+    /// See [`Self::read_guest_virt`] for how the address is translated.
+    pub fn write_guest_virt(&self, gva: GuestVirtAddr, buf: &[u8]) -> GuestMemResult {
+        self.write_guest_virt_as(gva, buf, self.trapped_in_supervisor())
+    }
+
+    /// Writes `buf` to guest memory at guest virtual address `gva`, with the privilege of the
+    /// guest's supervisor mode if `supervisor` is set. Write faults caused by dirty logging are
+    /// handled, and the write is retried.
+    fn write_guest_virt_as(
+        &self,
+        gva: GuestVirtAddr,
+        buf: &[u8],
+        supervisor: bool,
+    ) -> GuestMemResult {
+        // Each page written may be write-protected once.
+        let mut retries = (gva.as_usize() % PAGE_SIZE + buf.len()).div_ceil(PAGE_SIZE);
+        loop {
+            match guest_mem::write_guest_virt(gva, buf, supervisor) {
+                Err(fault)
+                    if retries > 0
+                        && 1usize.checked_shl(fault.scause as u32)
+                            == Some(traps::exception::STORE_GUEST_PAGE_FAULT)
+                        && self.handle_dirty_fault(fault.gpa()) =>
+                {
+                    retries -= 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Fetches the guest instruction at guest virtual address `gva`, as the guest would execute
+    /// it. Returns the instruction bits and its length in bytes.
+    ///
+    /// See [`Self::read_guest_virt`] for how the address is translated.
+    pub fn fetch_guest_insn(&self, gva: GuestVirtAddr) -> GuestMemResult<(u32, usize)> {
+        guest_mem::fetch_guest_insn(gva, self.trapped_in_supervisor())
+    }
+
+    /// Translates guest virtual address `gva` to a guest physical address by walking the guest's
+    /// page table in software, as last seen on a VM exit.
+    ///
+    /// The guest page table is read through the vCPU's G-stage page table, so this does not need
+    /// the vCPU to be bound to the current physical CPU.
+    pub fn translate_guest_virt(&self, gva: GuestVirtAddr) -> AxResult<GuestTranslation> {
+        page_walk::translate_guest_virt::<H::MmHal>(
+            self.regs.virtual_hs_csrs.hgatp,
+            self.regs.vs_csrs.vsatp,
+            gva,
+        )
+    }
+
+    /// Injects a synchronous exception into the guest, as if it was raised by the hardware and
+    /// delegated to VS-mode.
+    ///
+    /// `cause` is the exception code written to `vscause`, and `tval` is written to `vstval`. The
+    /// guest resumes at its trap vector the next time it runs. Must be called on the physical CPU
+    /// the vCPU is bound to, as the VS-level CSRs are accessed directly.
+    pub fn inject_exception(&mut self, cause: usize, tval: usize) {
+        let guest_sstatus = self.regs.guest_regs.sstatus;
+        let mut vsstatus: usize;
+        let vstvec: usize;
+        unsafe {
+            core::arch::asm!("csrr {}, vsstatus", out(reg) vsstatus);
+            core::arch::asm!("csrr {}, vstvec", out(reg) vstvec);
+        }
+
+        // The trap is taken from the privilege the guest was running at, with interrupts disabled.
+        let sie = vsstatus & SSTATUS_SIE != 0;
+        vsstatus &= !(SSTATUS_SPP | SSTATUS_SPIE | SSTATUS_SIE);
+        vsstatus |= guest_sstatus & SSTATUS_SPP;
+        if sie {
+            vsstatus |= SSTATUS_SPIE;
+        }
+
+        unsafe {
+            core::arch::asm!("csrw vsstatus, {}", in(reg) vsstatus);
+            core::arch::asm!("csrw vscause, {}", in(reg) cause);
+            core::arch::asm!("csrw vstval, {}", in(reg) tval);
+            core::arch::asm!("csrw vsepc, {}", in(reg) self.regs.guest_regs.sepc);
+        }
+
+        // Synchronous exceptions always go to the base address, even in vectored mode.
+        self.regs.guest_regs.sepc = vstvec & !STVEC_MODE_MASK;
+        // The guest handles the trap in VS-mode.
+        self.regs.guest_regs.sstatus |= SSTATUS_SPP;
+    }
 }
 
 impl<H: AxVCpuHal> RISCVVCpu<H> {
@@ -166,6 +593,13 @@ impl<H: AxVCpuHal> RISCVVCpu<H> {
         self.regs.trap_csrs.stval = stval::read();
         self.regs.trap_csrs.htval = htval::read();
         self.regs.trap_csrs.htinst = htinst::read();
+        self.regs.trap_csrs.sepc = self.regs.guest_regs.sepc;
+        self.regs.trap_csrs.hgeip = 0;
+        self.regs.trap_csrs.hstatus = self.regs.guest_regs.hstatus;
+        // Keep track of the guest page table for `translate_guest_virt`.
+        unsafe {
+            core::arch::asm!("csrr {}, vsatp", out(reg) self.regs.vs_csrs.vsatp);
+        }
 
         let scause = scause::read();
         use scause::{Exception, Interrupt, Trap};
@@ -189,11 +623,7 @@ impl<H: AxVCpuHal> RISCVVCpu<H> {
                     legacy::LEGACY_SET_TIMER..=legacy::LEGACY_SHUTDOWN => match extension_id {
                         legacy::LEGACY_SET_TIMER => {
                             // info!("set timer: {}", param[0]);
-                            sbi_rt::set_timer((param[0]) as u64);
-                            unsafe {
-                                // Clear guest timer interrupt
-                                hvip::clear_vstip();
-                            }
+                            self.set_timer(vclint::host_deadline(param[0] as u64));
 
                             self.set_gpr_from_gpr_index(GprIndex::A0, 0);
                         }
@@ -237,7 +667,13 @@ impl<H: AxVCpuHal> RISCVVCpu<H> {
                             let _opaque = a[2];
                             return Ok(AxVCpuExitReason::Halt);
                         }
-                        _ => todo!(),
+                        _ => {
+                            warn!("Unsupported SBI HSM function id {function_id:#x}");
+                            self.set_gpr_from_gpr_index(
+                                GprIndex::A0,
+                                sbi_spec::binary::RET_ERR_NOT_SUPPORTED,
+                            );
+                        }
                     },
                     // Handle hypercall
                     EID_HVC => {
@@ -274,36 +710,185 @@ impl<H: AxVCpuHal> RISCVVCpu<H> {
             }
             Trap::Interrupt(Interrupt::SupervisorTimer) => {
                 // Enable guest timer interrupt
+                self.inject_interrupt(VirtInterrupt::SupervisorTimer);
                 unsafe {
-                    hvip::set_vstip();
                     sie::set_stimer();
                 }
 
                 Ok(AxVCpuExitReason::Nothing)
             }
+            Trap::Interrupt(Interrupt::SupervisorSoft) => {
+                // A host IPI, acknowledge it and let the hypervisor process its IPI queue, also on
+                // a kick exit as the IPI may carry both.
+                unsafe {
+                    core::arch::asm!(
+                        "csrc sip, {ssip}",
+                        ssip = in(reg) traps::interrupt::SUPERVISOR_SOFT,
+                    );
+                }
+                let vector = if self.kick_requested.swap(false, Ordering::AcqRel) {
+                    KICK_EXIT_VECTOR
+                } else {
+                    traps::irq::S_SOFT
+                };
+                Ok(AxVCpuExitReason::ExternalInterrupt {
+                    vector: vector as _,
+                })
+            }
+            Trap::Interrupt(Interrupt::SupervisorGuestExternal) => {
+                // An MSI for a descheduled vCPU, the files that fired are in `trap_csrs.hgeip`.
+                self.regs.trap_csrs.hgeip = aia::take_guest_external_pending();
+                Ok(AxVCpuExitReason::ExternalInterrupt {
+                    vector: GUEST_EXTERNAL_EXIT_VECTOR as _,
+                })
+            }
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
-                Ok(AxVCpuExitReason::ExternalInterrupt { vector: 0 })
+                // Claim the interrupt here, as the host handler will not see the trap.
+                let vector = self
+                    .host_aia
+                    .then(aia::claim_host_external)
+                    .flatten()
+                    .unwrap_or_else(H::irq_fetch);
+                Ok(AxVCpuExitReason::ExternalInterrupt {
+                    vector: vector as _,
+                })
             }
-            Trap::Exception(Exception::LoadGuestPageFault)
+            Trap::Exception(Exception::InstructionGuestPageFault)
+            | Trap::Exception(Exception::LoadGuestPageFault)
             | Trap::Exception(Exception::StoreGuestPageFault) => {
-                let fault_addr = self.regs.trap_csrs.htval << 2 | self.regs.trap_csrs.stval & 0x3;
+                // Faults on implicit page table walks are reported with the address of the guest
+                // PTE, see `guest_page_fault` to tell them apart.
+                let fault = self.guest_page_fault().unwrap();
+                if fault.access_flags.contains(MappingFlags::WRITE)
+                    && self.handle_dirty_fault(fault.gpa)
+                {
+                    return Ok(AxVCpuExitReason::Nothing);
+                }
                 Ok(AxVCpuExitReason::NestedPageFault {
-                    addr: GuestPhysAddr::from(fault_addr),
-                    access_flags: MappingFlags::empty(),
+                    addr: fault.gpa,
+                    access_flags: fault.access_flags,
                 })
             }
+            Trap::Exception(Exception::LoadMisaligned)
+            | Trap::Exception(Exception::StoreMisaligned) => self.handle_misaligned_access(),
+            Trap::Exception(Exception::VirtualInstruction) => {
+                // The guest tried to execute an instruction that is not available in VS-mode and
+                // that we do not emulate, report it as an illegal instruction.
+                self.inject_exception(
+                    traps::exception::ILLEGAL_INST.trailing_zeros() as usize,
+                    self.regs.trap_csrs.stval,
+                );
+                Ok(AxVCpuExitReason::Nothing)
+            }
             _ => {
-                panic!(
-                    "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}",
+                // Do not bring down the hypervisor for a single misbehaving guest, let the VMM
+                // decide what to do with it. The details are kept in `trap_csrs`.
+                error!(
+                    "Unhandled trap: {:?}, sepc: {:#x}, stval: {:#x}, htval: {:#x}, htinst: {:#x}",
                     scause.cause(),
-                    self.regs.guest_regs.sepc,
-                    self.regs.trap_csrs.stval
+                    self.regs.trap_csrs.sepc,
+                    self.regs.trap_csrs.stval,
+                    self.regs.trap_csrs.htval,
+                    self.regs.trap_csrs.htinst
                 );
+                Err(AxError::BadState)
             }
         }
     }
 }
 
+impl<H: AxVCpuHal> RISCVVCpu<H> {
+    /// Emulates the misaligned load or store that trapped, with byte-wise guest memory accesses.
+    fn handle_misaligned_access(&mut self) -> AxResult<AxVCpuExitReason> {
+        let cause = self.regs.trap_csrs.scause;
+        let addr = self.regs.trap_csrs.stval;
+
+        let insn = if self.emulate_misaligned {
+            match self.decode_trapped_mem_insn() {
+                Ok(insn) => insn,
+                Err(fault) => return Ok(self.reflect_guest_mem_fault(fault)),
+            }
+        } else {
+            None
+        };
+        let Some(insn) = insn else {
+            // Not emulated, let the guest handle it.
+            self.inject_exception(cause, addr);
+            return Ok(AxVCpuExitReason::Nothing);
+        };
+
+        // Access the memory as the guest did, so that guest user mode cannot reach the pages of
+        // the guest kernel by misaligning its accesses.
+        let supervisor = self.trapped_in_supervisor();
+        let gva = GuestVirtAddr::from(addr);
+        let res = match insn.op {
+            MemOp::Load { rd, signed } => {
+                let mut buf = [0u8; 8];
+                guest_mem::read_guest_virt(gva, &mut buf[..insn.width], supervisor).map(|_| {
+                    let mut val = u64::from_le_bytes(buf) as usize;
+                    if signed {
+                        val = sign_extend(val, insn.width);
+                    }
+                    self.set_gpr_from_gpr_index(rd, val);
+                })
+            }
+            MemOp::Store { rs2 } => {
+                let buf = (self.get_gpr(rs2) as u64).to_le_bytes();
+                self.write_guest_virt_as(gva, &buf[..insn.width], supervisor)
+            }
+        };
+        match res {
+            Ok(()) => {
+                self.advance_pc(insn.len);
+                Ok(AxVCpuExitReason::Nothing)
+            }
+            Err(fault) => Ok(self.reflect_guest_mem_fault(fault)),
+        }
+    }
+
+    /// Decodes the load or store instruction that trapped, from `htinst` if the hardware provided
+    /// it, or by fetching it from guest memory.
+    ///
+    /// Returns `Ok(None)` if the instruction is not a load or store we can emulate.
+    fn decode_trapped_mem_insn(&self) -> GuestMemResult<Option<MemInsn>> {
+        let htinst = self.regs.trap_csrs.htinst;
+        if htinst != 0 {
+            return Ok(MemInsn::decode_transformed(htinst));
+        }
+        let (insn, _) = guest_mem::fetch_guest_insn(
+            GuestVirtAddr::from(self.regs.guest_regs.sepc),
+            self.trapped_in_supervisor(),
+        )?;
+        Ok(MemInsn::decode(insn))
+    }
+
+    /// Handles a fault raised while the hypervisor accessed guest memory on behalf of the guest.
+    ///
+    /// Faults in the guest's own address translation are injected into the guest, as instruction
+    /// fetch faults for the instruction fetches. Guest-page faults are reported to the VMM, so
+    /// that it can map the page and let the guest retry.
+    fn reflect_guest_mem_fault(&mut self, fault: GuestMemFault) -> AxVCpuExitReason {
+        let access_flags = match 1usize.checked_shl(fault.scause as u32).unwrap_or(0) {
+            traps::exception::INST_GUEST_PAGE_FAULT => MappingFlags::EXECUTE,
+            traps::exception::LOAD_GUEST_PAGE_FAULT => MappingFlags::READ,
+            traps::exception::STORE_GUEST_PAGE_FAULT => MappingFlags::WRITE,
+            _ => {
+                self.inject_exception(fault.scause, fault.stval);
+                return AxVCpuExitReason::Nothing;
+            }
+        };
+        AxVCpuExitReason::NestedPageFault {
+            addr: fault.gpa(),
+            access_flags,
+        }
+    }
+
+    /// Returns whether the guest was in supervisor mode when it last exited.
+    fn trapped_in_supervisor(&self) -> bool {
+        self.regs.guest_regs.sstatus & SSTATUS_SPP != 0
+    }
+}
+
 #[inline(always)]
 fn sbi_call_legacy_0(eid: usize) -> usize {
     let error;
@@ -0,0 +1,123 @@
+//! Virtual interrupts of the guest, injected through `hvip`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxError, AxResult};
+
+use crate::consts::traps;
+
+/// A VS-level interrupt that can be injected into the guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VirtInterrupt {
+    /// Virtual supervisor software interrupt (`VSSIP`).
+    SupervisorSoft,
+    /// Virtual supervisor timer interrupt (`VSTIP`).
+    SupervisorTimer,
+    /// Virtual supervisor external interrupt (`VSEIP`).
+    SupervisorExternal,
+}
+
+impl VirtInterrupt {
+    /// Returns the bit of the interrupt in `hvip`.
+    pub(crate) const fn hvip_bit(self) -> usize {
+        match self {
+            Self::SupervisorSoft => traps::interrupt::VIRTUAL_SUPERVISOR_SOFT,
+            Self::SupervisorTimer => traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
+            Self::SupervisorExternal => traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL,
+        }
+    }
+}
+
+/// Bits of `hvip` the guest can clear by itself, through `sip`.
+const GUEST_CLEARABLE: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT;
+
+/// The virtual interrupts pending for a vCPU, in the layout of `hvip`.
+///
+/// `hvip` belongs to the physical CPU, so the pending state is kept here and loaded into `hvip`
+/// each time the vCPU enters the guest. It can be updated from any CPU.
+#[derive(Debug, Default)]
+pub(crate) struct PendingVirtInterrupts {
+    pending: AtomicUsize,
+    // Interrupts injected since the vCPU last entered the guest.
+    injected: AtomicUsize,
+}
+
+impl PendingVirtInterrupts {
+    /// Marks `irq` as pending.
+    pub fn inject(&self, irq: VirtInterrupt) {
+        self.pending.fetch_or(irq.hvip_bit(), Ordering::AcqRel);
+        self.injected.fetch_or(irq.hvip_bit(), Ordering::AcqRel);
+    }
+
+    /// Marks `irq` as no longer pending.
+    pub fn clear(&self, irq: VirtInterrupt) {
+        self.pending.fetch_and(!irq.hvip_bit(), Ordering::AcqRel);
+    }
+
+    /// Returns the pending interrupts, in the layout of `hvip`.
+    pub fn bits(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Loads the pending interrupts into `hvip` before entering the guest.
+    ///
+    /// Returns the value written to `hvip`, to be given to [`Self::sync_from_hvip`] after the
+    /// guest exits.
+    pub fn load_to_hvip(&self) -> usize {
+        self.injected.store(0, Ordering::Release);
+        let hvip = self.bits();
+        unsafe {
+            core::arch::asm!("csrw hvip, {}", in(reg) hvip);
+        }
+        hvip
+    }
+
+    /// Takes the changes the guest made to `hvip` into account after it exits.
+    ///
+    /// `entry_hvip` is the value returned by [`Self::load_to_hvip`].
+    pub fn sync_from_hvip(&self, entry_hvip: usize) {
+        let hvip: usize;
+        unsafe {
+            core::arch::asm!("csrr {}, hvip", out(reg) hvip);
+        }
+        // Do not lose interrupts injected again while the guest was running.
+        let cleared = entry_hvip & !hvip & GUEST_CLEARABLE & !self.injected.load(Ordering::Acquire);
+        if cleared != 0 {
+            self.pending.fetch_and(!cleared, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Receives the virtual interrupt lines driven by the emulated interrupt controllers of this
+/// crate, e.g. [`VirtPlic`](crate::VirtPlic), [`VirtAplic`](crate::VirtAplic) and
+/// [`VirtClint`](crate::VirtClint).
+///
+/// Usually implemented by the VMM with [`RISCVVCpu::inject_interrupt`] and
+/// [`RISCVVCpu::clear_interrupt`] on the vCPU with the given ID.
+///
+/// [`RISCVVCpu::inject_interrupt`]: crate::RISCVVCpu::inject_interrupt
+/// [`RISCVVCpu::clear_interrupt`]: crate::RISCVVCpu::clear_interrupt
+pub trait VirtIrqSink {
+    /// Sets the level of the virtual interrupt `irq` of the vCPU `vcpu_id`.
+    fn set_irq_level(&self, vcpu_id: usize, irq: VirtInterrupt, level: bool);
+
+    /// Programs the virtual timer of the vCPU `vcpu_id` to fire at `deadline`, in ticks of the
+    /// `time` CSR of the host.
+    ///
+    /// Only used by emulated timer devices. Usually implemented with
+    /// [`RISCVVCpu::set_timer`](crate::RISCVVCpu::set_timer) on the CPU running the vCPU. The
+    /// error is returned to the guest access programming the timer, which is the default.
+    fn set_timer(&self, _vcpu_id: usize, _deadline: u64) -> AxResult {
+        Err(AxError::Unsupported)
+    }
+
+    /// Sends an MSI with identity `eiid` to the interrupt file `guest_index` of the vCPU
+    /// `vcpu_id`, where guest index 0 is the supervisor-level file.
+    ///
+    /// Only used by emulated controllers in MSI delivery mode. Usually implemented with
+    /// [`RISCVVCpu::send_msi`](crate::RISCVVCpu::send_msi) for guest index 0. The interrupt stays
+    /// pending in the controller while this fails, which is the default.
+    fn send_msi(&self, _vcpu_id: usize, _guest_index: usize, _eiid: usize) -> AxResult {
+        Err(AxError::Unsupported)
+    }
+}
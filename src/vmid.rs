@@ -0,0 +1,192 @@
+//! Allocation of VMIDs, which tag the G-stage TLB entries of the guests.
+//!
+//! A VM is identified by the root of its G-stage page table, shared by all its vCPUs. Each hart
+//! allocates its VMIDs on its own, to the VMs whose vCPUs are bound to it, so a VM has a VMID on
+//! each hart it runs on. When a hart runs out of VMIDs, it starts a new generation: its G-stage
+//! TLB is flushed, and the VMs get new VMIDs when their vCPUs are bound to it again.
+//!
+//! A hart remembers the VMIDs of its [`RECENT_VMS`] last VMs. The other VMs get a new VMID when
+//! bound to it again, and the translations tagged with their previous VMID are left in the TLB
+//! until the next generation, as VMIDs are not reused within a generation. Without VMID bits, all
+//! VMs share VMID 0, and flush the G-stage TLB of the hart whenever one of their vCPUs is bound
+//! to it.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::RISCVVCpuHostIf;
+use crate::percpu::HartState;
+use crate::tlb;
+
+/// Number of VMs whose VMIDs each hart remembers.
+const RECENT_VMS: usize = 16;
+/// The first VMID of a generation, 0 is left to the VMs without their own VMID.
+const FIRST_VMID: usize = 1;
+
+/// The VMIDs allocated by a hart in the current generation.
+pub(crate) struct HartVmids {
+    lock: AtomicBool,
+    inner: UnsafeCell<HartVmidsInner>,
+}
+
+struct HartVmidsInner {
+    // The next VMID to allocate.
+    next: usize,
+    // The G-stage root page tables of the last VMs, with their VMIDs.
+    vms: [Option<(usize, usize)>; RECENT_VMS],
+    // The entry of `vms` to replace next.
+    victim: usize,
+}
+
+// `inner` is only accessed with `lock` held.
+unsafe impl Sync for HartVmids {}
+
+impl HartVmids {
+    pub const fn new() -> Self {
+        Self {
+            lock: AtomicBool::new(false),
+            inner: UnsafeCell::new(HartVmidsInner {
+                next: FIRST_VMID,
+                vms: [None; RECENT_VMS],
+                victim: 0,
+            }),
+        }
+    }
+
+    /// Returns the VMID of the VM with G-stage root page table `root` on the current hart, with
+    /// `vmid_bits` VMID bits, and whether the G-stage TLB of the hart must be flushed before
+    /// using it.
+    fn get_or_alloc(&self, root: usize, vmid_bits: usize) -> (usize, bool) {
+        let mut vmids = self.lock();
+        // VMID 0 is shared by all VMs.
+        let shared = vmid_bits == 0;
+        if let Some(vmid) = vmids.find(root) {
+            return (vmid, shared);
+        }
+        let mut flush = shared;
+        let vmid = if shared {
+            0
+        } else {
+            if vmids.next >= 1 << vmid_bits {
+                // Start a new generation.
+                vmids.next = FIRST_VMID;
+                vmids.vms = [None; RECENT_VMS];
+                flush = true;
+            }
+            vmids.next += 1;
+            vmids.next - 1
+        };
+        let victim = vmids.victim;
+        vmids.vms[victim] = Some((root, vmid));
+        vmids.victim = (victim + 1) % RECENT_VMS;
+        (vmid, flush)
+    }
+
+    /// Returns the VMID of the VM with G-stage root page table `root` on the hart, if it has one.
+    fn get(&self, root: usize) -> Option<usize> {
+        self.lock().find(root)
+    }
+
+    fn lock(&self) -> HartVmidsGuard<'_> {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        HartVmidsGuard(self)
+    }
+}
+
+impl HartVmidsInner {
+    fn find(&self, root: usize) -> Option<usize> {
+        self.vms
+            .iter()
+            .flatten()
+            .find(|&&(vm, _)| vm == root)
+            .map(|&(_, vmid)| vmid)
+    }
+}
+
+/// Holds the lock of [`HartVmids`] until dropped.
+struct HartVmidsGuard<'a>(&'a HartVmids);
+
+impl Deref for HartVmidsGuard<'_> {
+    type Target = HartVmidsInner;
+
+    fn deref(&self) -> &HartVmidsInner {
+        unsafe { &*self.0.inner.get() }
+    }
+}
+
+impl DerefMut for HartVmidsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut HartVmidsInner {
+        unsafe { &mut *self.0.inner.get() }
+    }
+}
+
+impl Drop for HartVmidsGuard<'_> {
+    fn drop(&mut self) {
+        self.0.lock.store(false, Ordering::Release);
+    }
+}
+
+/// The VM of a vCPU, which gives the VMIDs of the VM.
+#[derive(Debug, Default)]
+pub(crate) struct VmVmid {
+    // The address of the G-stage root page table of the VM.
+    root: Option<usize>,
+}
+
+impl VmVmid {
+    /// Registers the vCPU with the VM of G-stage root page table `root`, leaving its previous VM.
+    pub fn register(&mut self, root: usize) {
+        self.release();
+        self.root = Some(root);
+    }
+
+    /// Returns the VMID of the VM on the current hart, with state `state`, and whether the
+    /// G-stage TLB of the hart must be flushed before using it.
+    pub fn vmid(&self, state: &HartState) -> (usize, bool) {
+        match self.root {
+            Some(root) => state.vmids.get_or_alloc(root, state.features().vmid_bits),
+            None => (0, true),
+        }
+    }
+
+    /// Invalidates the G-stage translations of guest physical addresses `start..start + size` of
+    /// the VM, or all of them if `range` is `None`, on all harts it has a VMID on.
+    ///
+    /// The current hart is flushed directly, the others through the SBI.
+    pub fn flush(&self, range: Option<(usize, usize)>) {
+        let Some(root) = self.root else {
+            return;
+        };
+        let current = crate_interface::call_interface!(RISCVVCpuHostIf::current_hart_id);
+        for state in HartState::all() {
+            let Some(vmid) = state.vmids.get(root) else {
+                continue;
+            };
+            if state.hart() == current {
+                tlb::flush_gstage(vmid, range, state.features().svinval);
+            } else {
+                tlb::remote_flush_gstage(Some(state.hart()), Some(vmid), range);
+            }
+        }
+    }
+
+    /// Leaves the VM, invalidating its VMIDs on all harts, so that a VM later using the same
+    /// root page table does not get the translations of this one.
+    fn release(&mut self) {
+        self.flush(None);
+        self.root = None;
+    }
+}
+
+impl Drop for VmVmid {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
@@ -0,0 +1,328 @@
+//! Emulated Platform-Level Interrupt Controller (PLIC) for guests.
+//!
+//! The register layout follows the RISC-V PLIC specification. Context `n` is the supervisor
+//! context of the vCPU with ID `n`, so the guest device tree should only list the supervisor
+//! external interrupt of each hart in the `interrupts-extended` property of the PLIC node.
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::consts::traps::irq::MAX_IRQ_COUNT;
+use crate::mmio::MmioAccess;
+use crate::virq::{VirtInterrupt, VirtIrqSink};
+
+/// Size of the PLIC MMIO region.
+pub const PLIC_MMIO_SIZE: usize = 0x400_0000;
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// Implemented priority bits.
+const PRIORITY_MASK: u32 = 0x7;
+/// Number of 32-bit words in a bitmap of all interrupt sources.
+const SOURCE_WORDS: usize = MAX_IRQ_COUNT / 32;
+
+/// An emulated PLIC with `CONTEXTS` supervisor contexts, one per vCPU.
+///
+/// Driven by guest MMIO accesses with [`Self::handle_mmio`], and by devices with
+/// [`Self::raise_source`] and [`Self::lower_source`]. The supervisor external interrupt of each
+/// vCPU is set through the [`VirtIrqSink`].
+pub struct VirtPlic<S: VirtIrqSink, const CONTEXTS: usize> {
+    base: GuestPhysAddr,
+    num_sources: usize,
+    priority: [u32; MAX_IRQ_COUNT],
+    pending: [u32; SOURCE_WORDS],
+    // Claimed but not yet completed sources, which are not forwarded again until completion.
+    in_service: [u32; SOURCE_WORDS],
+    enable: [[u32; SOURCE_WORDS]; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    // Current level of the external interrupt line of each context.
+    line: [bool; CONTEXTS],
+    sink: S,
+}
+
+impl<S: VirtIrqSink, const CONTEXTS: usize> VirtPlic<S, CONTEXTS> {
+    /// Creates a PLIC mapped at guest physical address `base`, with interrupt sources `1` to
+    /// `num_sources` (the `riscv,ndev` property of the device tree node).
+    pub fn new(base: GuestPhysAddr, num_sources: usize, sink: S) -> AxResult<Self> {
+        if num_sources >= MAX_IRQ_COUNT {
+            return ax_err!(InvalidInput, "too many PLIC interrupt sources");
+        }
+        Ok(Self {
+            base,
+            num_sources,
+            priority: [0; MAX_IRQ_COUNT],
+            pending: [0; SOURCE_WORDS],
+            in_service: [0; SOURCE_WORDS],
+            enable: [[0; SOURCE_WORDS]; CONTEXTS],
+            threshold: [0; CONTEXTS],
+            line: [false; CONTEXTS],
+            sink,
+        })
+    }
+
+    /// Returns the guest physical address the PLIC is mapped at.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns whether `addr` is in the MMIO region of the PLIC.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base.as_usize()..self.base.as_usize() + PLIC_MMIO_SIZE).contains(&addr.as_usize())
+    }
+
+    /// Raises interrupt source `source`.
+    ///
+    /// The source stays pending until it is claimed by a context, or lowered with
+    /// [`Self::lower_source`]. It is ignored while a previous interrupt of the source is being
+    /// serviced.
+    pub fn raise_source(&mut self, source: usize) -> AxResult {
+        self.check_source(source)?;
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        if self.in_service[word] & bit == 0 {
+            self.pending[word] |= bit;
+            self.update();
+        }
+        Ok(())
+    }
+
+    /// Lowers interrupt source `source`, for level-triggered devices that deassert their
+    /// interrupt before it is claimed.
+    pub fn lower_source(&mut self, source: usize) -> AxResult {
+        self.check_source(source)?;
+        self.pending[source / 32] &= !(1 << (source % 32));
+        self.update();
+        Ok(())
+    }
+
+    /// Handles the guest MMIO access `access`, and returns the value read (0 for writes).
+    pub fn handle_mmio(&mut self, access: &MmioAccess) -> AxResult<usize> {
+        if !self.contains(access.addr) {
+            return ax_err!(InvalidInput, "address out of the PLIC MMIO region");
+        }
+        // All registers are 32-bit wide.
+        if access.width != 4 || access.addr.as_usize() % 4 != 0 {
+            return ax_err!(InvalidInput, "unsupported PLIC access width");
+        }
+        let offset = access.addr.as_usize() - self.base.as_usize();
+        match access.write_data {
+            Some(val) => {
+                self.write(offset, val as u32);
+                Ok(0)
+            }
+            None => Ok(self.read(offset) as usize),
+        }
+    }
+
+    /// Reads the 32-bit register at `offset`. Reserved registers read as zero.
+    fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => self.priority[offset / 4],
+            PENDING_BASE..ENABLE_BASE if (offset - PENDING_BASE) / 4 < SOURCE_WORDS => {
+                self.pending[(offset - PENDING_BASE) / 4]
+            }
+            ENABLE_BASE..CONTEXT_BASE => match self.enable_reg(offset) {
+                Some((context, word)) => self.enable[context][word],
+                None => 0,
+            },
+            CONTEXT_BASE.. => match self.context_reg(offset) {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context],
+                Some((context, CONTEXT_CLAIM)) => self.claim(context),
+                _ => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    /// Writes the 32-bit register at `offset`. Writes to read-only and reserved registers are
+    /// ignored.
+    fn write(&mut self, offset: usize, val: u32) {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let source = offset / 4;
+                if source != 0 && source <= self.num_sources {
+                    self.priority[source] = val & PRIORITY_MASK;
+                }
+            }
+            ENABLE_BASE..CONTEXT_BASE => {
+                if let Some((context, word)) = self.enable_reg(offset) {
+                    self.enable[context][word] = val & self.valid_sources(word);
+                }
+            }
+            CONTEXT_BASE.. => match self.context_reg(offset) {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context] = val & PRIORITY_MASK,
+                Some((context, CONTEXT_CLAIM)) => self.complete(context, val as usize),
+                _ => {}
+            },
+            _ => {}
+        }
+        self.update();
+    }
+
+    /// Claims the highest priority interrupt pending for `context`, or returns 0 if there is
+    /// none.
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best_pending(context) else {
+            return 0;
+        };
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        self.pending[word] &= !bit;
+        self.in_service[word] |= bit;
+        self.update();
+        source as u32
+    }
+
+    /// Completes the interrupt `source` previously claimed by `context`.
+    fn complete(&mut self, context: usize, source: usize) {
+        if source == 0 || source > self.num_sources {
+            return;
+        }
+        let (word, bit) = (source / 32, 1 << (source % 32));
+        // Completions of sources not enabled for the context are ignored.
+        if self.enable[context][word] & bit != 0 {
+            self.in_service[word] &= !bit;
+        }
+    }
+
+    /// Returns the highest priority source pending and enabled for `context` above its
+    /// threshold. Ties are broken by the lowest source ID.
+    fn best_pending(&self, context: usize) -> Option<usize> {
+        let mut best: Option<(usize, u32)> = None;
+        for word in 0..SOURCE_WORDS {
+            let mut candidates = self.pending[word] & self.enable[context][word];
+            while candidates != 0 {
+                let source = word * 32 + candidates.trailing_zeros() as usize;
+                candidates &= candidates - 1;
+                let priority = self.priority[source];
+                if priority > self.threshold[context] && best.is_none_or(|(_, p)| priority > p) {
+                    best = Some((source, priority));
+                }
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    /// Updates the external interrupt line of each context.
+    fn update(&mut self) {
+        for context in 0..CONTEXTS {
+            let level = self.best_pending(context).is_some();
+            if level != self.line[context] {
+                self.line[context] = level;
+                self.sink
+                    .set_irq_level(context, VirtInterrupt::SupervisorExternal, level);
+            }
+        }
+    }
+
+    /// Returns the context and word index of the enable register at `offset`.
+    fn enable_reg(&self, offset: usize) -> Option<(usize, usize)> {
+        let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+        let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+        (context < CONTEXTS).then_some((context, word))
+    }
+
+    /// Returns the context and register offset within the context of the register at `offset`.
+    fn context_reg(&self, offset: usize) -> Option<(usize, usize)> {
+        let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+        let reg = (offset - CONTEXT_BASE) % CONTEXT_STRIDE;
+        (context < CONTEXTS).then_some((context, reg))
+    }
+
+    /// Returns the mask of existing sources in the bitmap word `word`.
+    fn valid_sources(&self, word: usize) -> u32 {
+        let mut mask = 0;
+        for bit in 0..32 {
+            let source = word * 32 + bit;
+            if source != 0 && source <= self.num_sources {
+                mask |= 1 << bit;
+            }
+        }
+        mask
+    }
+
+    fn check_source(&self, source: usize) -> AxResult {
+        if source == 0 || source > self.num_sources {
+            return ax_err!(InvalidInput, "invalid PLIC interrupt source");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    /// Records the external interrupt line of each context.
+    #[derive(Default)]
+    struct Lines(Cell<u32>);
+
+    impl VirtIrqSink for Lines {
+        fn set_irq_level(&self, vcpu_id: usize, irq: VirtInterrupt, level: bool) {
+            assert_eq!(irq, VirtInterrupt::SupervisorExternal);
+            let lines = self.0.get() & !(1 << vcpu_id);
+            self.0.set(lines | (level as u32) << vcpu_id);
+        }
+    }
+
+    fn plic() -> VirtPlic<Lines, 2> {
+        let mut plic = VirtPlic::new(GuestPhysAddr::from(0xc00_0000), 8, Lines::default()).unwrap();
+        // Sources 1 to 3 with priorities 1, 3 and 3, enabled for context 0.
+        plic.write(PRIORITY_BASE + 4, 1);
+        plic.write(PRIORITY_BASE + 8, 3);
+        plic.write(PRIORITY_BASE + 12, 3);
+        plic.write(ENABLE_BASE, 0b1110);
+        plic
+    }
+
+    const CLAIM_0: usize = CONTEXT_BASE + CONTEXT_CLAIM;
+
+    #[test]
+    fn claim_by_priority() {
+        let mut plic = plic();
+        for source in 1..=3 {
+            plic.raise_source(source).unwrap();
+        }
+        assert_eq!(plic.sink.0.get(), 0b01);
+        // Ties are broken by the lowest source ID.
+        assert_eq!(plic.read(CLAIM_0), 2);
+        assert_eq!(plic.read(CLAIM_0), 3);
+        assert_eq!(plic.sink.0.get(), 0b01);
+        assert_eq!(plic.read(CLAIM_0), 1);
+        assert_eq!(plic.sink.0.get(), 0);
+        assert_eq!(plic.read(CLAIM_0), 0);
+    }
+
+    #[test]
+    fn in_service_until_completed() {
+        let mut plic = plic();
+        plic.raise_source(2).unwrap();
+        assert_eq!(plic.read(CLAIM_0), 2);
+        plic.raise_source(2).unwrap();
+        assert_eq!(plic.read(PENDING_BASE), 0);
+        plic.write(CLAIM_0, 2);
+        plic.raise_source(2).unwrap();
+        assert_eq!(plic.read(PENDING_BASE), 0b100);
+        assert_eq!(plic.sink.0.get(), 0b01);
+    }
+
+    #[test]
+    fn threshold_masks_priorities() {
+        let mut plic = plic();
+        plic.write(CONTEXT_BASE + CONTEXT_THRESHOLD, 1);
+        plic.raise_source(1).unwrap();
+        assert_eq!(plic.sink.0.get(), 0);
+        assert_eq!(plic.read(CLAIM_0), 0);
+        plic.raise_source(3).unwrap();
+        assert_eq!(plic.sink.0.get(), 0b01);
+        assert_eq!(plic.read(CLAIM_0), 3);
+        assert_eq!(plic.read(PENDING_BASE), 0b10);
+    }
+}